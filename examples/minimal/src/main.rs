@@ -15,6 +15,31 @@ fn main() {
 
     let host = "localhost";
     let port = 5891;
-    let suite = Arc::new(suite);
-    wsmould::start((host.as_ref(), port), suite)
+    serve(host, port, Arc::new(suite));
+}
+
+/// Serves `wss://` if started with a PKCS#12 identity and its password.
+#[cfg(feature = "tls")]
+fn serve(host: &str, port: u16, suite: Arc<Suite<UserSession>>) {
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut args = env::args().skip(1);
+    match (args.next(), args.next()) {
+        (Some(identity), Some(password)) => {
+            let mut pkcs12 = Vec::new();
+            File::open(identity)
+                .and_then(|mut file| file.read_to_end(&mut pkcs12))
+                .expect("can't read the identity");
+            let acceptor = wsmould::tls_acceptor(&pkcs12, &password).expect("bad identity");
+            wsmould::start_tls((host, port), suite, acceptor)
+        }
+        _ => wsmould::start((host, port), suite),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn serve(host: &str, port: u16, suite: Arc<Suite<UserSession>>) {
+    wsmould::start((host, port), suite)
 }
@@ -15,7 +15,7 @@ impl<T> service::Service<T> for HelloService
     fn route(&self, action: &str) -> service::Result<Action<T>> {
         match action {
             "do-it" => {
-                Ok(Action::from_worker(do_it::DoItWorker))
+                Ok(Action::from_worker(do_it::DoItWorker).requires(Permission::CanDoIt))
             }
             _ => {
                 Err(service::Error::ActionNotFound.into())
@@ -39,3 +39,35 @@ mod do_it {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mould::server::Suite;
+    use mould::session::{DefaultBuilder, Input, TaskResult, Value};
+    use mould::testing::run_request;
+    use session::UserSession;
+    use super::HelloService;
+
+    #[test]
+    fn do_it_is_done() {
+        let mut suite: Suite<UserSession> = Suite::new(DefaultBuilder);
+        suite.register("hello", HelloService);
+        let input = Input {
+            id: 1,
+            service: "hello".into(),
+            action: "do-it".into(),
+            payload: Value::Null,
+            timeout_ms: None,
+        };
+        let outputs = run_request(&suite, UserSession::default(), input);
+        assert_eq!(outputs.len(), 2);
+        match outputs[0].result {
+            TaskResult::Item(ref item) => assert!(item.is_null()),
+            _ => panic!("no item"),
+        }
+        match outputs[1].result {
+            TaskResult::Done => (),
+            _ => panic!("not done"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Audit log of requests and responses of chosen services.
+//!
+//! Every entry is a line of JSON written by a separate thread, so a slow
+//! sink never blocks a session:
+//!
+//! * {"timestamp": ..., "who": "...", "id": 1, "service": "...", "action": "...", "payload": {...}}
+//! * {"timestamp": ..., "who": "...", "id": 1, "service": "...", "action": "...", "output": {...}}
+//!
+//! Payloads are logged as workers get them, after the input transform.
+//! Secrets can be dropped from an entry by a redactor before it's written.
+
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use serde_json;
+use session::{Output, TaskId, Value};
+use timesync::timestamp;
+
+/// Changes an entry before it's written, e.g. masks a password.
+pub type Redactor = Box<Fn(&mut Value) + Send + Sync>;
+
+pub struct AuditLog {
+    services: HashSet<String>,
+    sender: Mutex<Sender<String>>,
+    redactor: Option<Arc<Redactor>>,
+}
+
+impl AuditLog {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        let (tx, rx) = mpsc::channel::<String>();
+        thread::spawn(move || {
+            let mut writer = BufWriter::new(writer);
+            while let Ok(line) = rx.recv() {
+                let mut result = writeln!(writer, "{}", line);
+                // Flush once the queue is drained
+                while let Ok(line) = rx.try_recv() {
+                    result = result.and_then(|_| writeln!(writer, "{}", line));
+                }
+                if let Err(err) = result.and_then(|_| writer.flush()) {
+                    warn!("Can't write the audit log: {}", err);
+                }
+            }
+        });
+        AuditLog {
+            services: HashSet::new(),
+            sender: Mutex::new(tx),
+            redactor: None,
+        }
+    }
+
+    /// Adds a service to audit.
+    pub fn service(mut self, name: &str) -> Self {
+        self.services.insert(name.to_owned());
+        self
+    }
+
+    /// Sets a redactor which is called with every entry.
+    pub fn redact(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    pub fn is_audited(&self, service: &str) -> bool {
+        self.services.contains(service)
+    }
+
+    /// Creates a recorder for a session.
+    pub fn recorder(&self, who: &str) -> Recorder {
+        let sender = self.sender.lock().expect("audit log poisoned").clone();
+        Recorder {
+            who: who.to_owned(),
+            sender: sender,
+            redactor: self.redactor.clone(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: f64,
+    who: &'a str,
+    id: TaskId,
+    service: &'a str,
+    action: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<&'a Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<&'a Output>,
+}
+
+pub struct Recorder {
+    who: String,
+    sender: Sender<String>,
+    redactor: Option<Arc<Redactor>>,
+}
+
+impl Recorder {
+    pub fn request(&self, id: TaskId, service: &str, action: &str, payload: &Value) {
+        self.write(Entry {
+            timestamp: timestamp(),
+            who: &self.who,
+            id: id,
+            service: service,
+            action: action,
+            payload: Some(payload),
+            output: None,
+        });
+    }
+
+    pub fn output(&self, service: &str, action: &str, output: &Output) {
+        self.write(Entry {
+            timestamp: timestamp(),
+            who: &self.who,
+            id: output.id,
+            service: service,
+            action: action,
+            payload: None,
+            output: Some(output),
+        });
+    }
+
+    fn write(&self, entry: Entry) {
+        let line = serde_json::to_value(&entry).and_then(|mut entry| {
+            if let Some(ref redactor) = self.redactor {
+                redactor(&mut entry);
+            }
+            serde_json::to_string(&entry)
+        });
+        match line {
+            Ok(line) => {
+                if self.sender.send(line).is_err() {
+                    warn!("Audit log of {} is closed", self.who);
+                }
+            }
+            Err(err) => {
+                warn!("Can't serialize an audit entry of {}: {}", self.who, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Synchronous client of the protocol.
+//!
+//! A client sends requests over any flow and waits for their outputs,
+//! it's handy for integration tests and calls of other services:
+//!
+//! * `Client::connect("ws://localhost:5891")` talks to a websocket server
+//! * `Client::new(IoFlow::new(..))` talks to a process over its stdio
+//!
+//! Requests are performed one at a time, a client is used by one thread.
+
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use flow::{self, Flow};
+use session::{Input, Output, TaskId, TaskResult, Failure};
+#[cfg(feature = "wsmould")]
+use websocket::ClientBuilder;
+#[cfg(feature = "wsmould")]
+use server::wsmould::WsFlow;
+
+#[derive(Debug, Fail)]
+pub enum Error {
+    #[fail(display = "connection closed")]
+    ConnectionClosed,
+    #[fail(display = "flow error: {}", _0)]
+    FlowBroken(#[cause] flow::Error),
+    #[fail(display = "serde error: {}", _0)]
+    SerdeFailed(#[cause] serde_json::Error),
+    #[fail(display = "bad url: {}", _0)]
+    BadUrl(String),
+    /// The worker has failed the request
+    #[fail(display = "request failed: {}", _0)]
+    Failed(Failure),
+    #[fail(display = "request canceled")]
+    Canceled,
+    /// The server asks to reconnect to another node
+    #[fail(display = "redirect to {}", _0)]
+    Redirect(String),
+}
+
+impl From<flow::Error> for Error {
+    fn from(cause: flow::Error) -> Self {
+        Error::FlowBroken(cause)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(cause: serde_json::Error) -> Self {
+        Error::SerdeFailed(cause)
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Client which speaks JSON with a server over a flow.
+pub struct Client<F: Flow> {
+    flow: F,
+    last_id: TaskId,
+    /// Outputs of a frame which aren't read yet
+    received: VecDeque<Output>,
+}
+
+impl<F: Flow> Client<F> {
+    pub fn new(flow: F) -> Self {
+        Client {
+            flow: flow,
+            last_id: 0,
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Performs an action and returns its items when the worker is done.
+    /// Pages of a paged worker are asked for until the last one.
+    pub fn call<P, O>(&mut self, service: &str, action: &str, payload: P) -> Result<Vec<O>>
+    where
+        P: Serialize,
+        for<'de> O: Deserialize<'de>,
+    {
+        let outputs = self.request(service, action, payload)?;
+        let mut items = Vec::new();
+        for out in outputs {
+            if let TaskResult::Item(value) = out.result {
+                items.push(serde_json::from_value(value)?);
+            }
+        }
+        Ok(items)
+    }
+
+    /// Performs an action and returns every output of it, the last one
+    /// is `done`. Failures of the request are returned as errors.
+    pub fn request<P: Serialize>(&mut self, service: &str, action: &str, payload: P) -> Result<Vec<Output>> {
+        self.last_id += 1;
+        let id = self.last_id;
+        let input = Input {
+            id: id,
+            service: service.to_owned(),
+            action: action.to_owned(),
+            payload: serde_json::to_value(payload)?,
+            timeout_ms: None,
+        };
+        self.flow.push(serde_json::to_string(&input)?)?;
+        let mut outputs = Vec::new();
+        loop {
+            let out = self.recv()?;
+            // Notifications and outputs of former requests
+            if out.id != id {
+                continue;
+            }
+            match out.result {
+                TaskResult::More => {
+                    let next = format!("{{\"event\":\"next\",\"id\":{}}}", id);
+                    self.flow.push(next)?;
+                }
+                TaskResult::Done => {
+                    outputs.push(out);
+                    return Ok(outputs);
+                }
+                TaskResult::Fail(failure) => return Err(Error::Failed(failure)),
+                TaskResult::Cancelled => return Err(Error::Canceled),
+                TaskResult::Redirect { url } => return Err(Error::Redirect(url)),
+                _ => outputs.push(out),
+            }
+        }
+    }
+
+    /// Takes the next output, a frame can carry a batch of them.
+    fn recv(&mut self) -> Result<Output> {
+        loop {
+            if let Some(out) = self.received.pop_front() {
+                return Ok(out);
+            }
+            let content = self.flow.pull()?.ok_or(Error::ConnectionClosed)?;
+            for line in content.lines().filter(|line| !line.trim().is_empty()) {
+                self.received.push_back(serde_json::from_str(line)?);
+            }
+        }
+    }
+
+    /// Gives the flow back.
+    pub fn into_inner(self) -> F {
+        self.flow
+    }
+}
+
+#[cfg(feature = "wsmould")]
+impl Client<WsFlow> {
+    /// Connects to a websocket server, e.g. `ws://localhost:5891`.
+    pub fn connect(url: &str) -> Result<Self> {
+        let mut builder = ClientBuilder::new(url).map_err(|err| Error::BadUrl(err.to_string()))?;
+        let client = builder.connect_insecure().map_err(flow::Error::from)?;
+        Ok(Client::new(WsFlow::new(client)))
+    }
+}
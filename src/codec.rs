@@ -0,0 +1,263 @@
+//! Codecs of protocol messages. JSON in text frames is used by default,
+//! a suite can be set up with a binary codec for bandwidth-sensitive
+//! clients.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use session::{Error, Result, Inbound, Output, TaskId, Failure, code};
+
+/// Turns frames of a client into messages and outputs into frames.
+pub trait Codec: Send + Sync + 'static {
+    /// Binary codecs use binary frames, text ones are valid UTF-8 and
+    /// use text frames.
+    fn is_binary(&self) -> bool;
+
+    /// Splits a frame with several messages (a batch) into messages,
+    /// each is decoded and rejected alone. A frame is a single message
+    /// by default.
+    fn split(&self, content: Vec<u8>) -> Vec<Vec<u8>> {
+        vec![content]
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<Inbound>;
+
+    fn encode(&self, out: &Output) -> Result<Vec<u8>>;
+
+    /// Whether the first message of a client looks encoded with the
+    /// codec (see `Suite::detect_codec`). Nothing is recognized by default.
+    fn sniff(&self, _content: &[u8]) -> bool {
+        false
+    }
+}
+
+/// Largest integer which JavaScript numbers keep exactly, 2^53 - 1.
+const MAX_SAFE_INTEGER: u64 = 9_007_199_254_740_991;
+
+/// Messages as JSON objects.
+#[derive(Default)]
+pub struct JsonCodec {
+    lenient: bool,
+    pretty: bool,
+    big_ints_as_strings: bool,
+}
+
+impl JsonCodec {
+    /// With `lenient` a message followed by trailing data is accepted
+    /// and the data is ignored.
+    pub fn new(lenient: bool) -> Self {
+        JsonCodec {
+            lenient: lenient,
+            ..JsonCodec::default()
+        }
+    }
+
+    /// Indents outputs to read them while debugging. Pretty outputs
+    /// span lines, so clients can't take batches or NDJSON by lines.
+    pub fn pretty(mut self, enabled: bool) -> Self {
+        self.pretty = enabled;
+        self
+    }
+
+    /// Sends integers which JavaScript can't keep exactly as strings,
+    /// and takes ids of requests as strings back.
+    pub fn big_ints_as_strings(mut self, enabled: bool) -> Self {
+        self.big_ints_as_strings = enabled;
+        self
+    }
+
+    fn write<S: Serialize>(&self, value: &S) -> serde_json::Result<Vec<u8>> {
+        if self.pretty {
+            serde_json::to_vec_pretty(value)
+        } else {
+            serde_json::to_vec(value)
+        }
+    }
+}
+
+/// Turns integers out of the safe range of JavaScript into strings.
+fn stringify_big_ints(value: &mut Value) {
+    let big = match *value {
+        Value::Number(ref number) => {
+            number.as_u64().map_or(false, |n| n > MAX_SAFE_INTEGER) ||
+                number.as_i64().map_or(false, |n| n < -(MAX_SAFE_INTEGER as i64))
+        }
+        Value::Array(ref mut values) => {
+            values.iter_mut().for_each(stringify_big_ints);
+            false
+        }
+        Value::Object(ref mut map) => {
+            map.values_mut().for_each(stringify_big_ints);
+            false
+        }
+        _ => false,
+    };
+    if big {
+        *value = Value::String(value.to_string());
+    }
+}
+
+impl Codec for JsonCodec {
+    fn is_binary(&self) -> bool {
+        false
+    }
+
+    /// An object or a batch, whitespace may come first.
+    fn sniff(&self, content: &[u8]) -> bool {
+        match content.iter().find(|byte| !byte.is_ascii_whitespace()) {
+            Some(&b'{') | Some(&b'[') => true,
+            _ => false,
+        }
+    }
+
+    /// A batch is an array of messages. An array which can't be read
+    /// is left whole to be rejected by `decode`. Data after an array is
+    /// treated as after a single message, lenient codecs ignore it.
+    fn split(&self, content: Vec<u8>) -> Vec<Vec<u8>> {
+        let is_batch = content.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'[');
+        if !is_batch {
+            return vec![content];
+        }
+        let mut de = serde_json::Deserializer::from_slice(&content);
+        let messages = Vec::<Value>::deserialize(&mut de).and_then(|messages| {
+            if let Err(err) = de.end() {
+                if !self.lenient {
+                    return Err(err);
+                }
+                warn!("Trailing data of a batch ignored: {}", err);
+            }
+            messages.iter().map(serde_json::to_vec).collect()
+        });
+        messages.unwrap_or_else(|_| vec![content])
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<Inbound> {
+        let mut de = serde_json::Deserializer::from_slice(content);
+        let mut value = Value::deserialize(&mut de).map_err(|err| malformed(content, &err))?;
+        if let Err(err) = de.end() {
+            if !self.lenient {
+                return Err(malformed(content, &err));
+            }
+            warn!("Trailing data of a message ignored: {}", err);
+        }
+        if self.big_ints_as_strings {
+            let id = value.get("id").and_then(Value::as_str).and_then(|id| id.parse::<TaskId>().ok());
+            if let Some(id) = id {
+                value["id"] = Value::from(id);
+            }
+        }
+        Inbound::from_value(value)
+    }
+
+    fn encode(&self, out: &Output) -> Result<Vec<u8>> {
+        let encoded = if self.big_ints_as_strings {
+            let mut value = serde_json::to_value(out)?;
+            stringify_big_ints(&mut value);
+            self.write(&value)
+        } else {
+            self.write(out)
+        };
+        encoded.map_err(Error::from)
+    }
+}
+
+/// Rejects a message which isn't JSON against the id it seems to have.
+fn malformed(content: &[u8], err: &serde_json::Error) -> Error {
+    let failure = Failure::new(code::BAD_REQUEST, err.to_string());
+    Error::Malformed(salvage_id(content).unwrap_or(0), failure)
+}
+
+/// Looks for `"id": <number>` among top-level keys of a broken message,
+/// ids of nested objects and of batches aren't taken.
+fn salvage_id(content: &[u8]) -> Option<TaskId> {
+    let text = String::from_utf8_lossy(content);
+    let mut chars = text.char_indices();
+    let mut depth = 0;
+    let mut object = false;
+    let mut key = false;
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            '{' | '[' => {
+                if depth == 0 {
+                    object = c == '{';
+                }
+                depth += 1;
+                key = object && depth == 1;
+            }
+            '}' | ']' => depth -= 1,
+            ',' => key = object && depth == 1,
+            '"' => {
+                let mut escaped = false;
+                let end = chars.by_ref().find(|&(_, c)| {
+                    let closes = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    closes
+                });
+                let end = end?.0;
+                if key && &text[pos + 1..end] == "id" {
+                    let rest = text[end + 1..].trim_start();
+                    if !rest.starts_with(':') {
+                        return None;
+                    }
+                    let rest = rest[1..].trim_start();
+                    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                    return rest[..digits].parse().ok();
+                }
+                key = false;
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Messages as MessagePack maps with the same fields as JSON ones.
+#[cfg(feature = "msgpack")]
+#[derive(Default)]
+pub struct MsgPackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MsgPackCodec {
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    /// A map or an array, their markers aren't printable ASCII.
+    fn sniff(&self, content: &[u8]) -> bool {
+        match content.first() {
+            Some(&(0x80..=0x9f)) | Some(&(0xdc..=0xdf)) => true,
+            _ => false,
+        }
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<Inbound> {
+        let value: Value = rmp_serde::from_slice(content).map_err(|err| {
+            Error::CodecFailed(err.to_string())
+        })?;
+        Inbound::from_value(value)
+    }
+
+    fn encode(&self, out: &Output) -> Result<Vec<u8>> {
+        // Through a value to keep field names, structs are arrays otherwise
+        let value = serde_json::to_value(out)?;
+        rmp_serde::to_vec(&value).map_err(|err| Error::CodecFailed(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::salvage_id;
+
+    #[test]
+    fn id_of_top_level_key_is_salvaged() {
+        assert_eq!(salvage_id(br#"{"service": "x", "id": 7, "payload": "#), Some(7));
+        assert_eq!(salvage_id(br#"{"payload": {"id": 3}, "id": 5 trailing"#), Some(5));
+        assert_eq!(salvage_id(br#"{"payload": "\"id\": 4", "id": 6, "#), Some(6));
+    }
+
+    #[test]
+    fn nested_ids_are_ignored() {
+        assert_eq!(salvage_id(br#"{"payload": {"id": 3}, "service": "#), None);
+        assert_eq!(salvage_id(br#"{"service": "id", "payload": [{"id": 2}"#), None);
+        assert_eq!(salvage_id(br#"[{"id": 1}, {"id": 2"#), None);
+    }
+}
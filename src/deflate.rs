@@ -0,0 +1,157 @@
+//! Per-message compression of websocket connections (RFC 7692).
+//!
+//! The server compresses every message on its own, so clients keep no
+//! window for it. Messages of a client are inflated with a window kept
+//! for the whole connection, so clients may keep their own.
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::header::extensions::{Extension, Parameter};
+use websocket::message::OwnedMessage;
+use websocket::result::{WebSocketError, WebSocketResult};
+use websocket::ws::Message;
+
+pub const EXTENSION: &'static str = "permessage-deflate";
+
+/// Reason of the error of a message which is too large when inflated.
+pub const TOO_LARGE: &'static str = "inflated message too large";
+
+/// Messages shorter than it aren't worth compressing by default, they
+/// take a packet anyway.
+pub const MIN_COMPRESSED: usize = 1024;
+
+/// Tail which senders strip from compressed messages.
+const TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+pub struct Deflate {
+    inflater: Box<InflateState>,
+    /// Opcode, compression flag and data of a message not received whole
+    partial: Option<(Opcode, bool, Vec<u8>)>,
+}
+
+impl Deflate {
+    /// Accepts the first offer of a client the server can serve, returns
+    /// the extension to respond with. Offers which restrict the window
+    /// of the server can't be served.
+    pub fn negotiate(offers: &[Extension]) -> Option<(Self, Extension)> {
+        let acceptable = offers.iter().any(|offer| {
+            offer.name == EXTENSION && offer.params.iter().all(|param| {
+                param.name.starts_with("client_max_window_bits") ||
+                    param.name == "client_no_context_takeover" ||
+                    param.name == "server_no_context_takeover"
+            })
+        });
+        if !acceptable {
+            return None;
+        }
+        let deflate = Deflate {
+            inflater: InflateState::new_boxed(DataFormat::Raw),
+            partial: None,
+        };
+        let response = Extension {
+            name: EXTENSION.to_owned(),
+            params: vec![Parameter::new("server_no_context_takeover".to_owned(), None)],
+        };
+        Some((deflate, response))
+    }
+
+    /// Takes a frame of the client, gives a message when it's whole.
+    /// Inflated messages over `limit` bytes fail with `TOO_LARGE`.
+    pub fn accept(&mut self, frame: DataFrame, limit: Option<usize>) -> WebSocketResult<Option<OwnedMessage>> {
+        let finished = frame.finished;
+        match frame.opcode {
+            Opcode::Text | Opcode::Binary if self.partial.is_none() => {
+                self.partial = Some((frame.opcode, frame.reserved[0], frame.data));
+            }
+            Opcode::Continuation => {
+                match self.partial {
+                    Some((_, _, ref mut data)) => data.extend(frame.data),
+                    None => return Err(WebSocketError::ProtocolError("unexpected continuation")),
+                }
+            }
+            Opcode::Text | Opcode::Binary => {
+                return Err(WebSocketError::ProtocolError("unfinished message"));
+            }
+            // Control frames can come between fragments
+            _ => return OwnedMessage::from_dataframes(vec![frame]).map(Some),
+        }
+        if !finished {
+            return Ok(None);
+        }
+        let (opcode, compressed, data) = self.partial.take().expect("message has begun");
+        let data = if compressed { self.inflate(data, limit)? } else { data };
+        OwnedMessage::from_dataframes(vec![DataFrame::new(true, opcode, data)]).map(Some)
+    }
+
+    fn inflate(&mut self, mut data: Vec<u8>, limit: Option<usize>) -> WebSocketResult<Vec<u8>> {
+        data.extend_from_slice(&TAIL);
+        let mut input = &data[..];
+        let mut output = Vec::new();
+        let mut chunk = [0; 4096];
+        let mut over = false;
+        loop {
+            let result = inflate(&mut self.inflater, input, &mut chunk, MZFlush::Sync);
+            input = &input[result.bytes_consumed..];
+            // The rest is inflated anyway to keep the window of the client
+            over = over || limit.map_or(false, |limit| output.len() + result.bytes_written > limit);
+            if !over {
+                output.extend_from_slice(&chunk[..result.bytes_written]);
+            }
+            match result.status {
+                // A message compressed on its own ends its stream
+                Ok(MZStatus::StreamEnd) => {
+                    self.inflater.reset(DataFormat::Raw);
+                    break;
+                }
+                Ok(_) if input.is_empty() && result.bytes_written < chunk.len() => break,
+                Ok(_) => (),
+                Err(MZError::Buf) => break,
+                Err(_) => return Err(WebSocketError::DataFrameError("bad compressed data")),
+            }
+        }
+        if over {
+            return Err(WebSocketError::DataFrameError(TOO_LARGE));
+        }
+        Ok(output)
+    }
+
+    /// Makes a frame of a message, it's compressed if it has at least
+    /// `min_compressed` bytes.
+    pub fn frame(opcode: Opcode, data: &[u8], min_compressed: usize) -> DataFrame {
+        if data.len() < min_compressed {
+            return DataFrame::new(true, opcode, data.to_vec());
+        }
+        let mut frame = DataFrame::new(true, opcode, compress_to_vec(data, 6));
+        frame.reserved[0] = true;
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use websocket::dataframe::Opcode;
+    use websocket::header::extensions::Extension;
+    use websocket::message::OwnedMessage;
+    use super::{Deflate, EXTENSION};
+
+    #[test]
+    fn compressed_and_plain_frames_mix() {
+        let offer = Extension {
+            name: EXTENSION.to_owned(),
+            params: Vec::new(),
+        };
+        let (mut deflate, _) = Deflate::negotiate(&[offer]).expect("offer is acceptable");
+        let small = vec![b'a'; 100];
+        let large = vec![b'b'; 5000];
+        for data in &[&small, &large, &small, &large] {
+            let frame = Deflate::frame(Opcode::Binary, data, 1024);
+            assert_eq!(frame.reserved[0], data.len() >= 1024);
+            match deflate.accept(frame, None).unwrap() {
+                Some(OwnedMessage::Binary(ref message)) if message == *data => (),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+use std::time::Duration;
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -5,12 +7,204 @@ pub enum Error {
     ConnectionBroken,
     #[fail(display = "bad message encoding")]
     BadMessageEncoding,
+    #[fail(display = "line too long")]
+    LineTooLong,
+    #[fail(display = "message too large")]
+    MessageTooLarge,
+    #[fail(display = "binary frames unsupported")]
+    BinaryUnsupported,
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+/// Transport-level counters of a flow.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FlowStats {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub frames_received: u64,
+    pub frames_sent: u64,
+    pub pings_sent: u64,
+    /// Time the session thread has spent performing actions, a flow
+    /// leaves it zero and the session fills it in
+    pub blocking: Duration,
+    /// Time background tasks have waited for room in the channel of
+    /// outputs, the client reads slower than they emit. The session
+    /// fills it in too
+    pub output_wait: Duration,
+}
+
+/// What is known about the client of a flow when its session starts.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    who: String,
+    peer_addr: Option<SocketAddr>,
+    uri: Option<String>,
+    headers: Vec<(String, String)>,
+    protocol: Option<String>,
+    session_id: usize,
+}
+
+impl ConnectionInfo {
+    pub fn new(who: String, peer_addr: Option<SocketAddr>) -> Self {
+        ConnectionInfo {
+            who: who,
+            peer_addr: peer_addr,
+            uri: None,
+            headers: Vec::new(),
+            protocol: None,
+            session_id: 0,
+        }
+    }
+
+    /// Sets the id of the session which serves the flow.
+    pub fn with_session_id(mut self, id: usize) -> Self {
+        self.session_id = id;
+        self
+    }
+
+    /// Adds the upgrade request of a websocket connection.
+    pub fn handshake(
+        mut self,
+        uri: String,
+        headers: Vec<(String, String)>,
+        protocol: Option<String>,
+    ) -> Self {
+        self.uri = Some(uri);
+        self.headers = headers;
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn who(&self) -> &str {
+        &self.who
+    }
+
+    /// Address of the client if the flow is a network connection.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// URI of the upgrade request with its query string.
+    pub fn uri(&self) -> Option<&str> {
+        self.uri.as_ref().map(String::as_str)
+    }
+
+    /// Value of a parameter of the query string of the URI as is,
+    /// it isn't percent-decoded.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        let uri = self.uri.as_ref()?;
+        let query = uri.splitn(2, '?').nth(1)?;
+        query.split('&').find(|pair| pair.starts_with(name) && pair[name.len()..].starts_with('=')).map(|pair| {
+            &pair[name.len() + 1..]
+        })
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// Value of a handshake header, the name is case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| value.as_str())
+    }
+
+    /// Id of the session unique within the process, `0` if the flow
+    /// isn't served by a session yet.
+    pub fn session_id(&self) -> usize {
+        self.session_id
+    }
+
+    /// Subprotocol which the server has echoed to the client.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(String::as_str)
+    }
+}
+
+/// What a flow has got when it was polled.
+pub enum Pull {
+    Message(String),
+    Binary(Vec<u8>),
+    /// Nothing was received yet
+    Empty,
+    Closed,
+}
+
+/// Transport of a single session.
+///
+/// A flow is owned by the loop of its session (`process_session`) and
+/// `push` takes `&mut self`, so frames of a session are written one at a
+/// time by that loop and never interleave. Code running on other threads
+/// mustn't write to a flow directly, it has to hand outputs to the loop.
 pub trait Flow {
     fn who(&self) -> String;
+
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::new(self.who(), None)
+    }
+
     fn pull(&mut self) -> Result<Option<String>>;
     fn push(&mut self, content: String) -> Result<()>;
+
+    /// Takes a message only if it's already received. The session loop
+    /// polls a flow this way while background tasks are running.
+    /// Flows which can't be polled wait for a message.
+    fn try_pull(&mut self) -> Result<Pull> {
+        Ok(match self.pull()? {
+            Some(content) => Pull::Message(content),
+            None => Pull::Closed,
+        })
+    }
+
+    /// Sends a binary frame. Only flows polled by `try_pull` get binary
+    /// frames of clients, so only they carry binary codecs.
+    fn push_binary(&mut self, _content: Vec<u8>) -> Result<()> {
+        Err(Error::BinaryUnsupported)
+    }
+
+    fn stats(&self) -> FlowStats {
+        FlowStats::default()
+    }
+
+    /// Tells the other side that the flow is closing and why.
+    fn close(&mut self, _code: u16, _reason: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, F: Flow + ?Sized> Flow for &'a mut F {
+    fn who(&self) -> String {
+        (**self).who()
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        (**self).info()
+    }
+
+    fn pull(&mut self) -> Result<Option<String>> {
+        (**self).pull()
+    }
+
+    fn try_pull(&mut self) -> Result<Pull> {
+        (**self).try_pull()
+    }
+
+    fn push(&mut self, content: String) -> Result<()> {
+        (**self).push(content)
+    }
+
+    fn push_binary(&mut self, content: Vec<u8>) -> Result<()> {
+        (**self).push_binary(content)
+    }
+
+    fn stats(&self) -> FlowStats {
+        (**self).stats()
+    }
+
+    fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        (**self).close(code, reason)
+    }
 }
@@ -6,8 +6,17 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate futures;
 #[cfg(feature = "wsmould")]
 extern crate websocket;
+#[cfg(feature = "wss")]
+extern crate native_tls;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+#[cfg(feature = "deflate")]
+extern crate miniz_oxide;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 pub mod service;
 pub mod worker;
@@ -16,6 +25,16 @@ pub mod server;
 pub mod prelude;
 pub mod flow;
 pub mod permission;
+pub mod record;
+pub mod audit;
+pub mod timesync;
+pub mod pool;
+pub mod codec;
+pub mod resume;
+pub mod testing;
+pub mod client;
+#[cfg(feature = "deflate")]
+mod deflate;
 
 pub use session::Session;
 pub use session::Builder;
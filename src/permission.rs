@@ -1,16 +1,60 @@
-
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "access denied")]
     AccessDenied,
+    #[fail(display = "access denied, requires: {}", _0)]
+    AccessDeniedTo(String),
+    #[fail(display = "access denied, requires any of: {}", _0)]
+    AccessDeniedAny(String),
+    #[fail(display = "access denied, also requires: {}", _0)]
+    AccessDeniedAll(String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-pub trait Rights {}
+pub trait Rights {
+    /// Name of the right in denials, an unnamed right is denied with
+    /// the bare `AccessDenied`.
+    fn name(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Names of all the rights, if every one of them has a name.
+fn names<'a, R: Rights + 'a, I: Iterator<Item = &'a R>>(rights: I) -> Option<String> {
+    let names: Option<Vec<String>> = rights.map(Rights::name).collect();
+    names.map(|names| names.join(", "))
+}
 
 pub trait Require<R: Rights> {
     fn require(&self, right: &R) -> Result<()>;
+
+    /// Passes if at least one of `rights` is held. An empty list holds
+    /// no right, so it's denied with `AccessDenied`.
+    fn require_any(&self, rights: &[R]) -> Result<()> {
+        if rights.is_empty() {
+            return Err(Error::AccessDenied);
+        }
+        if rights.iter().any(|right| self.require(right).is_ok()) {
+            Ok(())
+        } else {
+            Err(names(rights.iter()).map_or(Error::AccessDenied, Error::AccessDeniedAny))
+        }
+    }
+
+    /// Passes if every one of `rights` is held, a denial lists the
+    /// missing ones. An empty list passes.
+    fn require_all(&self, rights: &[R]) -> Result<()> {
+        let missing: Vec<&R> = rights
+            .iter()
+            .filter(|right| self.require(right).is_err())
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(names(missing.into_iter()).map_or(Error::AccessDenied, Error::AccessDeniedAll))
+        }
+    }
 }
 
 impl<T: HasRight<R>, R: Rights> Require<R> for T {
@@ -18,7 +62,7 @@ impl<T: HasRight<R>, R: Rights> Require<R> for T {
         if self.has_right(right) {
             Ok(())
         } else {
-            Err(Error::AccessDenied)
+            Err(right.name().map_or(Error::AccessDenied, Error::AccessDeniedTo))
         }
     }
 }
@@ -26,3 +70,23 @@ impl<T: HasRight<R>, R: Rights> Require<R> for T {
 pub trait HasRight<R: Rights> {
     fn has_right(&self, right: &R) -> bool;
 }
+
+/// Checks that a session has at least one of the rights:
+/// `require_any!(session, Permission::CanEditAny, Permission::IsOwner)?;`
+#[macro_export]
+macro_rules! require_any {
+    ($session:expr, $($right:expr),+ $(,)*) => {{
+        use $crate::permission::Require;
+        $session.require_any(&[$($right),+])
+    }};
+}
+
+/// Checks that a session has every one of the rights:
+/// `require_all!(session, Permission::CanEdit, Permission::CanPublish)?;`
+#[macro_export]
+macro_rules! require_all {
+    ($session:expr, $($right:expr),+ $(,)*) => {{
+        use $crate::permission::Require;
+        $session.require_all(&[$($right),+])
+    }};
+}
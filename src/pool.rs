@@ -0,0 +1,48 @@
+//! Threads which run background tasks of all sessions of a suite.
+
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender};
+
+trait Run: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Run for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+pub struct Pool {
+    sender: Mutex<Sender<Box<Run>>>,
+}
+
+impl Pool {
+    pub fn new(size: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Box<Run>>();
+        let rx = Arc::new(Mutex::new(rx));
+        for n in 0..size {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("mould-pool-{}", n))
+                .spawn(move || loop {
+                    // Threads end when the pool is dropped
+                    let job = match rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    job.run();
+                })
+                .expect("can't spawn a pool thread");
+        }
+        Pool {
+            sender: Mutex::new(tx),
+        }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        // Receivers live as long as the pool
+        self.sender.lock().unwrap().send(Box::new(job)).ok();
+    }
+}
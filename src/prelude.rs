@@ -4,6 +4,6 @@ pub use session::Session;
 
 pub use service::{self, Action};
 
-pub use worker::{self, Worker};
+pub use worker::{self, Worker, StreamWorker, BackgroundWorker, PagedWorker, AsyncWorker, Job, Stream, Bulk};
 
 pub use permission::{Rights, Require};
@@ -0,0 +1,180 @@
+//! Recording and replaying of flows to reproduce sessions.
+//!
+//! Records are stored as newline-delimited JSON, one frame per line:
+//!
+//! * {"direction": "in", "timestamp": 1508160000000, "content": "..."}
+//! * {"direction": "out", "timestamp": 1508160000005, "content": "..."}
+//!
+//! Timestamp is a count of milliseconds since the unix epoch.
+
+use std::io::{self, BufRead, Write};
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json;
+use flow::{self, Flow, FlowStats, Pull, ConnectionInfo};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Frame {
+    pub direction: Direction,
+    pub timestamp: u64,
+    pub content: String,
+}
+
+impl Frame {
+    fn now(direction: Direction, content: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|dur| dur.as_secs() * 1000 + (dur.subsec_nanos() / 1_000_000) as u64)
+            .unwrap_or(0);
+        Frame {
+            direction: direction,
+            timestamp: timestamp,
+            content: content,
+        }
+    }
+}
+
+/// Wraps any flow and tees every pulled and pushed frame to a writer.
+pub struct RecordingFlow<F: Flow, W: Write> {
+    flow: F,
+    writer: W,
+}
+
+impl<F: Flow, W: Write> RecordingFlow<F, W> {
+    pub fn new(flow: F, writer: W) -> Self {
+        RecordingFlow {
+            flow: flow,
+            writer: writer,
+        }
+    }
+
+    fn record(&mut self, direction: Direction, content: &str) {
+        let frame = Frame::now(direction, content.to_owned());
+        let result = serde_json::to_writer(&mut self.writer, &frame)
+            .map_err(io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"))
+            .and_then(|_| self.writer.flush());
+        // Broken record mustn't break the session
+        if let Err(err) = result {
+            warn!("Can't record a frame of {}: {}", self.flow.who(), err);
+        }
+    }
+}
+
+impl<F: Flow, W: Write> Flow for RecordingFlow<F, W> {
+    fn who(&self) -> String {
+        self.flow.who()
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        self.flow.info()
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        let content = self.flow.pull()?;
+        if let Some(ref content) = content {
+            self.record(Direction::In, content);
+        }
+        Ok(content)
+    }
+
+    fn try_pull(&mut self) -> flow::Result<Pull> {
+        let pulled = self.flow.try_pull()?;
+        if let Pull::Message(ref content) = pulled {
+            self.record(Direction::In, content);
+        }
+        Ok(pulled)
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        self.record(Direction::Out, &content);
+        self.flow.push(content)
+    }
+
+    // Records keep text frames only
+    fn push_binary(&mut self, content: Vec<u8>) -> flow::Result<()> {
+        self.flow.push_binary(content)
+    }
+
+    fn stats(&self) -> FlowStats {
+        self.flow.stats()
+    }
+
+    fn close(&mut self, code: u16, reason: &str) -> flow::Result<()> {
+        self.flow.close(code, reason)
+    }
+}
+
+/// Feeds inbound frames of a record back and collects pushed frames.
+///
+/// Pass it to `process_session` as `&mut ReplayFlow` and compare
+/// `pushed` with `expected` when the session ends.
+pub struct ReplayFlow {
+    who: String,
+    inbound: VecDeque<String>,
+    expected: Vec<String>,
+    pushed: Vec<String>,
+}
+
+impl ReplayFlow {
+    pub fn new(who: &str, frames: Vec<Frame>) -> Self {
+        let mut inbound = VecDeque::new();
+        let mut expected = Vec::new();
+        for frame in frames {
+            match frame.direction {
+                Direction::In => inbound.push_back(frame.content),
+                Direction::Out => expected.push(frame.content),
+            }
+        }
+        ReplayFlow {
+            who: who.to_owned(),
+            inbound: inbound,
+            expected: expected,
+            pushed: Vec::new(),
+        }
+    }
+
+    pub fn from_reader<R: BufRead>(who: &str, reader: R) -> io::Result<Self> {
+        let mut frames = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            frames.push(serde_json::from_str(&line)?);
+        }
+        Ok(ReplayFlow::new(who, frames))
+    }
+
+    /// Outbound frames of the original session.
+    pub fn expected(&self) -> &[String] {
+        &self.expected
+    }
+
+    /// Outbound frames of the replayed session.
+    pub fn pushed(&self) -> &[String] {
+        &self.pushed
+    }
+}
+
+impl Flow for ReplayFlow {
+    fn who(&self) -> String {
+        self.who.clone()
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        Ok(self.inbound.pop_front())
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        self.pushed.push(content);
+        Ok(())
+    }
+}
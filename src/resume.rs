@@ -0,0 +1,131 @@
+//! Resumption of sessions of clients which reconnect.
+//!
+//! A suite with a store sends a token to every new client first:
+//!
+//! * {"id": 0, "result": {"event": "resume", "data": {"token": "..."}}}
+//!
+//! When the client disconnects its session is saved under the token. A
+//! client which reconnects with `?resume=<token>` in the URI (or the
+//! `Mould-Resume` header) gets the saved session instead of a new one
+//! from the builder, and a new token. A token can be used once.
+//!
+//! Sessions kept by `SnapshotStore` are values, so a store shared by
+//! processes lets a client resume its session on another node, e.g.
+//! while a node is redeployed.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde_json::Value;
+use flow::ConnectionInfo;
+use session::{Builder, Persistable};
+
+/// Query parameter of the URI which carries a token.
+pub const RESUME_PARAM: &'static str = "resume";
+
+/// Handshake header which carries a token.
+pub const RESUME_HEADER: &'static str = "Mould-Resume";
+
+/// Keeps sessions of clients which have gone.
+pub trait SessionStore<T>: Send + Sync + 'static {
+    fn save(&self, token: String, session: T);
+
+    /// Takes a session back, `None` if there is none or it has expired.
+    fn restore(&self, token: &str) -> Option<T>;
+}
+
+/// Keeps sessions in memory for `ttl` after their clients have gone.
+pub struct MemoryStore<T> {
+    ttl: Duration,
+    sessions: Mutex<HashMap<String, (Instant, T)>>,
+}
+
+impl<T> MemoryStore<T> {
+    pub fn new(ttl: Duration) -> Self {
+        MemoryStore {
+            ttl: ttl,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T: Send + 'static> SessionStore<T> for MemoryStore<T> {
+    fn save(&self, token: String, session: T) {
+        let ttl = self.ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, &mut (saved, _)| saved.elapsed() < ttl);
+        sessions.insert(token, (Instant::now(), session));
+    }
+
+    fn restore(&self, token: &str) -> Option<T> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.remove(token) {
+            Some((saved, session)) if saved.elapsed() < self.ttl => Some(session),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps snapshots of `Persistable` sessions in a store of values, the
+/// builder restores sessions from them. Snapshots which can't be
+/// restored are dropped, the client gets a new session.
+pub struct SnapshotStore<S, B> {
+    snapshots: S,
+    builder: B,
+}
+
+impl<S, B> SnapshotStore<S, B> {
+    pub fn new(snapshots: S, builder: B) -> Self {
+        SnapshotStore {
+            snapshots: snapshots,
+            builder: builder,
+        }
+    }
+}
+
+impl<T, S, B> SessionStore<T> for SnapshotStore<S, B>
+where
+    T: Persistable,
+    S: SessionStore<Value>,
+    B: Builder<T>,
+{
+    fn save(&self, token: String, session: T) {
+        self.snapshots.save(token, session.snapshot());
+    }
+
+    fn restore(&self, token: &str) -> Option<T> {
+        let snapshot = self.snapshots.restore(token)?;
+        match self.builder.restore(snapshot) {
+            Ok(session) => Some(session),
+            Err(err) => {
+                warn!("Can't restore a session: {}", err);
+                None
+            }
+        }
+    }
+}
+
+/// Token a client has presented to resume its session.
+pub fn presented(info: &ConnectionInfo) -> Option<&str> {
+    info.query(RESUME_PARAM).or_else(|| info.header(RESUME_HEADER))
+}
+
+/// Makes a token which can't be guessed by other clients.
+pub fn token(session_id: usize) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.subsec_nanos())
+        .unwrap_or(0);
+    // Hashers of `RandomState` have random keys
+    let mut parts = Vec::new();
+    for salt in 0..2u8 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(session_id);
+        hasher.write_u32(nanos);
+        hasher.write_u8(salt);
+        parts.push(format!("{:016x}", hasher.finish()));
+    }
+    parts.concat()
+}
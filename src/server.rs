@@ -1,12 +1,159 @@
-use std::collections::HashMap;
-use service::{self, Service};
-use session::{self, Context, Input, Output, Builder, Session};
+use std::borrow::Cow;
+use std::thread;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
+use std::collections::{HashMap, BTreeMap};
+use std::panic::{self, AssertUnwindSafe};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use service::{self, Service, FnService, Task};
+use session::{self, Context, Input, Inbound, Output, TaskId, TaskResult, Failure, Builder, BuildError, Session, Transactional, Value, REJECT_CODE, IDLE_CODE, code};
 use worker;
-use flow::Flow;
+use flow::{self, Flow, FlowStats, ConnectionInfo};
+use codec::Codec;
+use audit::{AuditLog, Recorder};
+use pool::Pool;
+use resume::{self, SessionStore};
+
+/// Describes where an error reported to the error handler has happened.
+pub struct ErrorContext<'a> {
+    pub who: &'a str,
+    /// Id of the request, it's `0` if the request wasn't read
+    pub id: TaskId,
+    pub service: Option<&'a str>,
+    pub action: Option<&'a str>,
+}
+
+pub type ErrorHandler = Box<Fn(&Error, &ErrorContext) + Send + Sync>;
+
+pub type InputTransform = Box<Fn(Value) -> ::std::result::Result<Value, Cow<'static, str>> + Send + Sync>;
+
+/// Reserved service which answers whether an action could be called.
+///
+/// Request `{"service": "__can__", "action": "", "payload": {"service":
+/// "hello", "action": "do-it"}}` returns `{"allowed": true}` or
+/// `{"allowed": false, "reason": "..."}` and doesn't perform anything.
+/// Routing and rights declared by the action are checked, rights demanded
+/// by a worker itself are unknown until it performs.
+pub const CAN_SERVICE: &'static str = "__can__";
+
+#[derive(Deserialize)]
+pub struct CanQuery {
+    pub service: String,
+    pub action: String,
+}
+
+#[derive(Serialize)]
+pub struct CanAnswer {
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+pub type CanGuard<T> = Box<Fn(&T) -> bool + Send + Sync>;
+
+/// Hooks around requests of every service (to log, to time or to check
+/// them).
+pub trait Middleware<T: Session>: Send + Sync + 'static {
+    /// Called before a request is routed, an error rejects the request.
+    fn before(&self, _input: &Input, _session: &mut T) -> worker::Result<()> {
+        Ok(())
+    }
+
+    /// Called for every output sent to the client. Outputs of a worker
+    /// which isn't in background are seen after it performed.
+    fn after(&self, _output: &Output, _session: &mut T) {}
+}
+
+/// Sink of events of sessions to count them (for a monitoring system).
+/// Every event is ignored by default.
+pub trait Metrics: Send + Sync + 'static {
+    fn session_opened(&self, _info: &ConnectionInfo) {}
+
+    fn session_closed(&self, _session_id: usize, _stats: FlowStats) {}
+
+    /// A request is received, it isn't checked yet.
+    fn request_received(&self, _service: &str, _action: &str) {}
+
+    /// A request which was routed has got its terminal result. A task in
+    /// background completes when the result is delivered.
+    fn task_completed(&self, _service: &str, _action: &str, _duration: Duration, _result: &TaskResult) {}
+
+    /// Outputs of background tasks are delivered, `pending` more wait in
+    /// the channel of the session or in tasks blocked on it. Tasks have
+    /// waited `waited` for room in it since the last delivery (see
+    /// `Suite::set_output_capacity`).
+    fn outputs_delivered(&self, _session_id: usize, _pending: usize, _waited: Duration) {}
+
+    /// An error is caught, the same as ones the error handler gets.
+    fn error(&self, _error: &Error, _context: &ErrorContext) {}
+}
+
+/// Reserved service which lists registered services with their actions.
+///
+/// Request `{"service": "__introspect__", "action": "", "payload": null}`
+/// returns `{"hello": ["do-it"], ...}` with actions each service
+/// advertises by `Service::actions`.
+pub const INTROSPECT_SERVICE: &'static str = "__introspect__";
+
+/// Reserved service with `begin`, `commit` and `rollback` actions
+/// of `Transactional` sessions.
+pub const TX_SERVICE: &'static str = "__tx__";
+
+/// Reserved service which logs a client in when the suite has an
+/// authenticator. Until a `__auth__` request succeeds requests of other
+/// services are denied with `ACCESS_DENIED`.
+pub const AUTH_SERVICE: &'static str = "__auth__";
+
+/// Checks a request of `AUTH_SERVICE` (credentials in its payload) and
+/// keeps who has logged in in the session.
+pub type Authenticator<T> = Box<Fn(&Input, &mut T) -> worker::Result<()> + Send + Sync>;
+
+/// Outputs a turn delivers at most before it reads the client. Tasks
+/// which emit faster wait on the channel, a `cancel` is never starved.
+const DRAIN_LIMIT: usize = 256;
+
+type Hook<T> = fn(&mut T) -> worker::Result<()>;
+
+struct Transactions<T> {
+    begin: Hook<T>,
+    commit: Hook<T>,
+    rollback: Hook<T>,
+}
 
 pub struct Suite<T: Session> {
     builder: Box<Builder<T>>,
     services: HashMap<String, Box<Service<T>>>,
+    ndjson: bool,
+    codec: Option<Arc<Codec>>,
+    detected: Vec<Arc<Codec>>,
+    batching: (usize, usize),
+    pool: Option<Arc<Pool>>,
+    compute_budget: Option<(Duration, Duration)>,
+    rate_limit: Option<f64>,
+    cancel_grace: Option<Duration>,
+    task_timeout: Option<Duration>,
+    page_wait: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    output_capacity: Option<usize>,
+    max_connections: Option<usize>,
+    max_message_bytes: Option<usize>,
+    max_output_bytes: Option<usize>,
+    close_on_oversized: bool,
+    session_threads: Option<usize>,
+    error_handler: Option<ErrorHandler>,
+    input_transform: Option<InputTransform>,
+    can_guard: Option<CanGuard<T>>,
+    authenticator: Option<Authenticator<T>>,
+    middlewares: Vec<Box<Middleware<T>>>,
+    metrics: Option<Box<Metrics>>,
+    store: Option<Box<SessionStore<T>>>,
+    audit_log: Option<AuditLog>,
+    transactions: Option<Transactions<T>>,
 }
 
 impl<T: Session> Suite<T> {
@@ -14,18 +161,337 @@ impl<T: Session> Suite<T> {
         Suite {
             builder: Box::new(builder),
             services: HashMap::new(),
+            ndjson: false,
+            codec: None,
+            detected: Vec::new(),
+            batching: (1, 1),
+            pool: None,
+            compute_budget: None,
+            rate_limit: None,
+            cancel_grace: None,
+            task_timeout: None,
+            page_wait: None,
+            idle_timeout: None,
+            output_capacity: None,
+            max_connections: None,
+            max_message_bytes: None,
+            max_output_bytes: None,
+            close_on_oversized: false,
+            session_threads: None,
+            error_handler: None,
+            input_transform: None,
+            can_guard: None,
+            authenticator: None,
+            middlewares: Vec::new(),
+            metrics: None,
+            store: None,
+            audit_log: None,
+            transactions: None,
+        }
+    }
+
+    /// Sets a handler which is called for every error caught by a session.
+    /// Closing of a connection, cancelling and redirects aren't reported.
+    pub fn on_error(&mut self, handler: ErrorHandler) {
+        self.error_handler = Some(handler);
+    }
+
+    /// Sets a transform which is applied to every payload before routing
+    /// (to decrypt or to normalize it). It gets a payload already decoded
+    /// from a message. An error rejects the request.
+    pub fn on_input(&mut self, transform: InputTransform) {
+        self.input_transform = Some(transform);
+    }
+
+    /// Requires every client to log in with an `AUTH_SERVICE` request
+    /// first. A client can log in again on the same connection.
+    pub fn set_authenticator(&mut self, authenticator: Authenticator<T>) {
+        self.authenticator = Some(authenticator);
+    }
+
+    /// Hides the registered services and actions from `CAN_SERVICE`
+    /// and `INTROSPECT_SERVICE` requests of sessions the guard doesn't
+    /// pass.
+    pub fn guard_can(&mut self, guard: CanGuard<T>) {
+        self.can_guard = Some(guard);
+    }
+
+    /// Adds a middleware, hooks of middlewares are called in the order
+    /// they were added.
+    pub fn add_middleware<M: Middleware<T>>(&mut self, middleware: M) {
+        self.middlewares.push(Box::new(middleware));
+    }
+
+    fn before(&self, session: &mut T, input: &Input) -> worker::Result<()> {
+        for middleware in &self.middlewares {
+            middleware.before(input, session)?;
+        }
+        Ok(())
+    }
+
+    fn after(&self, session: &mut T, output: &Output) {
+        for middleware in &self.middlewares {
+            middleware.after(output, session);
+        }
+    }
+
+    pub fn set_metrics<M: Metrics>(&mut self, metrics: M) {
+        self.metrics = Some(Box::new(metrics));
+    }
+
+    /// Saves sessions of clients which have gone, so they can resume
+    /// them by a token (see `resume` module).
+    pub fn set_session_store<S: SessionStore<T>>(&mut self, store: S) {
+        self.store = Some(Box::new(store));
+    }
+
+    /// Sets a log of requests and outputs of the services it audits.
+    pub fn set_audit_log(&mut self, log: AuditLog) {
+        self.audit_log = Some(log);
+    }
+
+    fn is_audited(&self, service: &str) -> bool {
+        self.audit_log.as_ref().map_or(false, |log| log.is_audited(service))
+    }
+
+    /// Lists registered services with the actions they advertise.
+    pub fn introspect(&self) -> BTreeMap<&str, Vec<&'static str>> {
+        self.services
+            .iter()
+            .map(|(name, service)| (name.as_str(), service.actions()))
+            .collect()
+    }
+
+    fn can(&self, session: &T, query: &CanQuery) -> CanAnswer {
+        if let Some(ref guard) = self.can_guard {
+            if !guard(session) {
+                return CanAnswer { allowed: false, reason: None };
+            }
+        }
+        let routed = match self.services.get(&query.service) {
+            Some(service) => service.route_with(&query.action, session).map_err(|err| err.to_string()),
+            None => Err(Error::ServiceNotFound.to_string()),
+        };
+        let routed = routed.and_then(|action| match action.requires {
+            Some(ref requires) => requires(session).map_err(|err| err.to_string()),
+            None => Ok(()),
+        });
+        match routed {
+            Ok(_) => CanAnswer { allowed: true, reason: None },
+            Err(reason) => CanAnswer { allowed: false, reason: Some(reason) },
+        }
+    }
+
+    /// Performs a `TX_SERVICE` action, `open` tracks a transaction
+    /// of the session.
+    fn transact(&self, session: &mut T, action: &str, open: &mut bool) -> Result<()> {
+        let hooks = self.transactions.as_ref().ok_or(Error::ServiceNotFound)?;
+        match action {
+            "begin" if *open => return Err(Error::TransactionBegun),
+            "begin" => (hooks.begin)(session)?,
+            "commit" | "rollback" if !*open => return Err(Error::NoTransaction),
+            "commit" => (hooks.commit)(session)?,
+            "rollback" => (hooks.rollback)(session)?,
+            _ => return Err(service::Error::ActionNotFound.into()),
+        }
+        *open = action == "begin";
+        Ok(())
+    }
+
+    fn report_error(&self, error: &Error, context: &ErrorContext) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.error(error, context);
+        }
+        if let Some(ref handler) = self.error_handler {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| handler(error, context)));
+            if result.is_err() {
+                warn!("Error handler panicked while reporting {:?}", error);
+            }
         }
     }
 
+    /// Lets clients pack newline-delimited requests into one message,
+    /// a request may continue in the next message.
+    pub fn set_ndjson(&mut self, enabled: bool) {
+        self.ndjson = enabled;
+    }
+
+    /// Encodes messages with the codec instead of JSON. Binary codecs
+    /// need a flow which carries binary frames (websockets). Extra data
+    /// after a message is accepted with `JsonCodec::new(true)`.
+    pub fn set_codec<C: Codec>(&mut self, codec: C) {
+        self.codec = Some(Arc::new(codec));
+    }
+
+    /// Adds a codec the first message of a client may be encoded with.
+    /// The first codec which recognizes it (`Codec::sniff`) serves the
+    /// session till its end, the codec of `set_codec` serves it if none
+    /// does. Outputs sent before the first message use that one too.
+    pub fn detect_codec<C: Codec>(&mut self, codec: C) {
+        self.detected.push(Arc::new(codec));
+    }
+
+    /// Packs from `min` to `max` outputs of background tasks into one
+    /// newline-delimited message when the client falls behind.
+    pub fn set_batching(&mut self, min: usize, max: usize) {
+        self.batching = (min, max);
+    }
+
+    /// Runs background tasks of all sessions on a pool of `size` threads
+    /// instead of a thread per task.
+    pub fn set_pool_size(&mut self, size: usize) {
+        self.pool = Some(Arc::new(Pool::new(size)));
+    }
+
+    /// Limits the time a session may block its thread performing actions
+    /// in every `period`, requests of a session over it are rejected
+    /// until the next period. Background tasks aren't counted, the time
+    /// spent is in `blocking` of the session stats.
+    pub fn set_compute_budget(&mut self, budget: Duration, period: Duration) {
+        self.compute_budget = Some((budget, period));
+    }
+
+    /// Sets how long a canceled background task may take to stop before
+    /// the client is told it's cancelled anyway.
+    pub fn set_cancel_grace(&mut self, grace: Duration) {
+        self.cancel_grace = Some(grace);
+    }
+
+    /// Fails background tasks which run longer than `timeout` with
+    /// `TIMEOUT`, unless an action has a timeout of its own. Actions which
+    /// aren't in background can't be stopped and run untimed, a compute
+    /// budget bounds them.
+    pub fn set_task_timeout(&mut self, timeout: Duration) {
+        self.task_timeout = Some(timeout);
+    }
+
+    /// Fails a paged task with `TIMEOUT` if its client doesn't ask for
+    /// the next page within `wait`, so it doesn't hold a thread forever.
+    pub fn set_page_wait(&mut self, wait: Duration) {
+        self.page_wait = Some(wait);
+    }
+
+    /// Closes a session with `IDLE_CODE` if its client sends nothing for
+    /// `timeout` while no background tasks run. Unlike websocket pings it
+    /// bounds silence of the application, pongs don't count. Flows which
+    /// can't be polled aren't closed.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Limits outputs of background tasks of a session waiting for a slow
+    /// client (1024 by default), a task over it blocks until they're sent.
+    /// Blocked tasks keep their threads, a pool too.
+    pub fn set_output_capacity(&mut self, capacity: usize) {
+        self.output_capacity = Some(capacity);
+    }
+
+    /// Limits simultaneous sessions of a server started with the suite,
+    /// extra clients are told that the server is busy and disconnected.
+    /// Websocket clients get `503 Service Unavailable` before any
+    /// handshake, `wss://` ones are just disconnected.
+    pub fn set_max_connections(&mut self, limit: usize) {
+        self.max_connections = Some(limit);
+    }
+
+    /// Lets a session make `per_sec` requests a second on average, with
+    /// bursts of the same size. Requests over it are rejected with
+    /// `RATE_LIMITED`, cancels aren't limited.
+    pub fn set_rate_limit(&mut self, per_sec: f64) {
+        self.rate_limit = Some(per_sec);
+    }
+
+    /// Limits the size of a message which servers started with the suite
+    /// accept. A bigger message is rejected with `TOO_LARGE` and the
+    /// session goes on, unless `set_close_on_oversized` is turned on.
+    pub fn set_max_message_bytes(&mut self, limit: usize) {
+        self.max_message_bytes = Some(limit);
+    }
+
+    /// Fails a request with `TOO_LARGE` once its worker has sent more than
+    /// `limit` bytes of items, unless its action has a budget of its own
+    /// (see `Action::max_output_bytes`).
+    pub fn set_max_output_bytes(&mut self, limit: usize) {
+        self.max_output_bytes = Some(limit);
+    }
+
+    /// Ends a session after its oversized message was rejected, a client
+    /// which sends one is likely broken.
+    pub fn set_close_on_oversized(&mut self, close: bool) {
+        self.close_on_oversized = close;
+    }
+
+    /// Turns sessions of a server started with the suite on `threads`
+    /// threads instead of a thread per connection. A worker performing
+    /// on a session thread holds up other sessions of that thread, long
+    /// work has to be done by background workers then. Panics if
+    /// `threads` is zero, no session could be served.
+    pub fn set_session_threads(&mut self, threads: usize) {
+        assert!(threads > 0, "sessions need at least one thread");
+        self.session_threads = Some(threads);
+    }
+
+    /// Registers a service under the name, a service registered
+    /// before with the same name is replaced.
     pub fn register<S: Service<T>>(&mut self, name: &str, service: S) {
         self.services.insert(name.to_owned(), Box::new(service));
     }
+
+    /// Registers a service of one action performed by the closure, more
+    /// actions can be added with `FnService`.
+    pub fn register_fn<F, I, O>(&mut self, name: &str, action: &'static str, handler: F)
+    where
+        for<'de> I: Deserialize<'de>,
+        O: Serialize,
+        F: Fn(&mut T, I) -> worker::Result<O> + Send + Sync + 'static,
+    {
+        self.register(name, FnService::new().action(action, handler));
+    }
+
+    /// Removes a service, requests for it fail with `ServiceNotFound`.
+    /// Returns `false` if there was no such service.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.services.remove(name).is_some()
+    }
+
+    pub fn has_service(&self, name: &str) -> bool {
+        self.services.contains_key(name)
+    }
+
+    pub fn service_names(&self) -> Vec<&str> {
+        self.services.keys().map(String::as_str).collect()
+    }
+}
+
+impl<T: Session + Transactional> Suite<T> {
+    /// Lets clients control transactions with `TX_SERVICE` requests.
+    pub fn enable_transactions(&mut self) {
+        self.transactions = Some(Transactions {
+            begin: T::begin,
+            commit: T::commit,
+            rollback: T::rollback,
+        });
+    }
 }
 
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "service not found")]
     ServiceNotFound,
+    #[fail(display = "transaction already begun")]
+    TransactionBegun,
+    #[fail(display = "no transaction")]
+    NoTransaction,
+    #[fail(display = "request id is already running")]
+    DuplicateId,
+    #[fail(display = "session over compute budget")]
+    OverBudget,
+    #[fail(display = "too many requests")]
+    RateLimited,
+    #[fail(display = "login required")]
+    Unauthenticated,
+    #[fail(display = "input rejected: {}", _0)]
+    InputRejected(Cow<'static, str>),
     #[fail(display = "cannot suspend")]
     CannotSuspend,
     #[fail(display = "cannot resume")]
@@ -38,6 +504,28 @@ pub enum Error {
     SessionFailed(#[cause] session::Error),
 }
 
+impl Error {
+    /// Failure which the client gets for the error.
+    pub fn failure(&self) -> Failure {
+        match *self {
+            Error::ServiceNotFound => Failure::new(code::SERVICE_NOT_FOUND, self.to_string()),
+            Error::ServiceFailed(service::Error::ActionNotFound) => {
+                Failure::new(code::ACTION_NOT_FOUND, service::Error::ActionNotFound.to_string())
+            }
+            Error::TransactionBegun | Error::NoTransaction | Error::DuplicateId => {
+                Failure::new(code::CONFLICT, self.to_string())
+            }
+            Error::OverBudget => Failure::new(code::OVER_BUDGET, self.to_string()),
+            Error::RateLimited => Failure::new(code::RATE_LIMITED, self.to_string()),
+            Error::Unauthenticated => Failure::new(code::ACCESS_DENIED, self.to_string()),
+            Error::InputRejected(_) => Failure::new(code::BAD_REQUEST, self.to_string()),
+            Error::WorkerFailed(ref cause) => cause.failure(),
+            Error::SessionFailed(ref cause) => cause.failure(),
+            _ => Failure::new(code::INTERNAL_ERROR, self.to_string()),
+        }
+    }
+}
+
 impl From<service::Error> for Error {
     fn from(cause: service::Error) -> Self {
         Error::ServiceFailed(cause)
@@ -58,233 +546,2155 @@ impl From<session::Error> for Error {
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
-pub fn process_session<T, R>(suite: &Suite<T>, rut: R)
-where
-    T: Session,
-    R: Flow,
-{
-
-    let who = rut.who();
-
-    debug!("Start session with {}", who);
+/// State of an accept loop shared with its handle.
+struct Listening {
+    shutdown: AtomicBool,
+    sessions: AtomicUsize,
+}
 
-    let mut session: Context<T, R> = Context::new(rut, suite.builder.build());
+/// Counts a session of a server while it lives.
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+struct SessionGuard(Arc<Listening>);
 
-    loop {
-        // Session loop
-        debug!("Begin new request processing for {}", who);
-        let result: Result<()> = (|session: &mut Context<T, R>| {
-            loop {
-                // Request loop
-                let Input { service, action, payload } = session.recv()?;
-                let service = suite.services.get(&service).ok_or(Error::ServiceNotFound)?;
-
-                let mut worker = service.route(&action)?;
-                let output = (worker.perform)(session, payload)?;
-                session.send(Output::Item(output))?;
-            }
-        })(&mut session);
-        // Inform user if
-        if let Err(reason) = result {
-            let output = match reason {
-                // TODO Refactor cancel (rename to StopAll and add CancelWorker)
-                Error::SessionFailed(session::Error::Canceled) => continue,
-                Error::SessionFailed(session::Error::FlowBroken(_)) => break,
-                Error::SessionFailed(session::Error::ConnectionClosed) => break,
-                _ => {
-                    warn!(
-                        "Request processing {} have catch an error {:?}",
-                        who,
-                        reason
-                    );
-                    Output::Fail(reason.to_string())
-                }
-            };
-            session.send(output).unwrap();
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+impl SessionGuard {
+    /// Counts a new session, fails if the server is full.
+    fn enter<T: Session>(listening: &Arc<Listening>, suite: &Suite<T>) -> Option<Self> {
+        let sessions = listening.sessions.load(Ordering::SeqCst);
+        if suite.max_connections.map_or(false, |limit| sessions >= limit) {
+            return None;
         }
+        Some(SessionGuard::new(listening.clone()))
     }
-    debug!("Ends session with {}", who);
 
-    // Standard sequence! Only one task simultaneous!
-    // Simple to debug, Simple to implement client, corresponds to websocket main principle!
+    fn new(listening: Arc<Listening>) -> Self {
+        listening.sessions.fetch_add(1, Ordering::SeqCst);
+        SessionGuard(listening)
+    }
+}
 
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.sessions.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
-#[cfg(feature = "wsmould")]
-pub mod wsmould {
-    use std::thread;
-    use std::io::ErrorKind;
-    use std::sync::Arc;
-    use std::net::{ToSocketAddrs, TcpStream};
-    use std::str::Utf8Error;
-    use std::time::{SystemTime, Duration};
-    use websocket::sync::Server;
-    use websocket::message::{OwnedMessage, Message};
-    use websocket::sync::Client;
-    use websocket::result::WebSocketError;
-    use session::Session;
-    use flow::{self, Flow};
+/// Hands sessions to a fixed number of threads, each of them turns
+/// its sessions in a round.
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+struct Driver<R: Flow + Send + 'static> {
+    threads: Vec<Sender<(R, SessionGuard)>>,
+    next: usize,
+}
 
-    impl From<WebSocketError> for flow::Error {
-        fn from(_: WebSocketError) -> Self {
-            flow::Error::ConnectionBroken
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+impl<R: Flow + Send + 'static> Driver<R> {
+    fn new<T: Session>(suite: Arc<Suite<T>>, threads: usize) -> Self {
+        let threads = (0..threads)
+            .map(|n| {
+                let (tx, rx) = mpsc::channel();
+                let suite = suite.clone();
+                thread::Builder::new()
+                    .name(format!("mould-driver-{}", n))
+                    .spawn(move || drive(suite.as_ref(), rx))
+                    .expect("can't spawn a driver thread");
+                tx
+            })
+            .collect();
+        Driver {
+            threads: threads,
+            next: 0,
         }
     }
 
-    impl From<Utf8Error> for flow::Error {
-        fn from(_: Utf8Error) -> Self {
-            flow::Error::BadMessageEncoding
-        }
+    /// Picks a thread for the next session.
+    fn sender(&mut self) -> Sender<(R, SessionGuard)> {
+        let sender = self.threads[self.next].clone();
+        self.next = (self.next + 1) % self.threads.len();
+        sender
     }
 
-    impl Flow for Client<TcpStream> {
-        fn who(&self) -> String {
-            let ip = self.peer_addr().unwrap();
-            format!("WS IP {}", ip)
-        }
+    fn run(&mut self, flow: R, guard: SessionGuard) {
+        // A thread lives while the driver does
+        self.sender().send((flow, guard)).ok();
+    }
+}
 
-        fn pull(&mut self) -> Result<Option<String>, flow::Error> {
-            let mut last_ping = SystemTime::now();
-            let ping_interval = Duration::from_secs(20);
-            loop {
-                let message = self.recv_message();
-                match message {
-                    Ok(message) => {
-                        match message {
-                            OwnedMessage::Text(content) => {
-                                return Ok(Some(content));
-                            }
-                            OwnedMessage::Close(_) => {
-                                return Ok(None);
-                            }
-                            OwnedMessage::Ping(payload) => {
-                                self.send_message(&Message::pong(payload))?;
-                            }
-                            OwnedMessage::Pong(payload) => {
-                                trace!("pong received: {:?}", payload);
-                            }
-                            OwnedMessage::Binary(_) => (),
-                        }
-                        // No need ping if interaction was successful
-                        last_ping = SystemTime::now();
-                    }
-                    Err(WebSocketError::IoError(ref err))
-                        if err.kind() == ErrorKind::WouldBlock => {
-                        let elapsed = last_ping
-                            .elapsed()
-                            .map(|dur| dur > ping_interval)
-                            .unwrap_or(false);
-                        if elapsed {
-                            // Reset time to stop ping flood
-                            last_ping = SystemTime::now();
-                            trace!("sending ping");
-                            self.send_message(&Message::ping("mould-ping".as_bytes()))?;
-                        }
-                        thread::sleep(Duration::from_millis(50));
-                    }
-                    Err(err) => {
-                        return Err(flow::Error::from(err));
+#[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+fn drive<T: Session, R: Flow>(suite: &Suite<T>, incoming: Receiver<(R, SessionGuard)>) {
+    let mut sessions: Vec<(SessionLoop<T, R>, SessionGuard)> = Vec::new();
+    let mut accepting = true;
+    loop {
+        while accepting {
+            match incoming.try_recv() {
+                Ok((flow, guard)) => {
+                    if let Ok(session) = SessionLoop::new(suite, flow) {
+                        sessions.push((session, guard));
                     }
                 }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => accepting = false,
             }
         }
-
-        fn push(&mut self, content: String) -> Result<(), flow::Error> {
-            self.send_message(&Message::text(content)).map_err(
-                flow::Error::from,
-            )
+        if !accepting && sessions.is_empty() {
+            break;
+        }
+        let mut idle = true;
+        let mut i = 0;
+        while i < sessions.len() {
+            match sessions[i].0.turn(false) {
+                Turn::Progressed => {
+                    idle = false;
+                    i += 1;
+                }
+                Turn::Idle => i += 1,
+                Turn::Ended => {
+                    sessions.swap_remove(i);
+                }
+            }
+        }
+        if idle {
+            thread::sleep(Duration::from_millis(10));
         }
     }
+}
 
+/// Controls a server which accepts connections in background.
+pub struct ServerHandle {
+    listening: Arc<Listening>,
+    addr: SocketAddr,
+    thread: thread::JoinHandle<()>,
+}
 
-
-    pub fn start<T, A>(addr: A, suite: Arc<super::Suite<T>>)
+impl ServerHandle {
+    #[cfg(any(feature = "wsmould", feature = "tcpmould"))]
+    fn spawn<F>(addr: SocketAddr, serve: F) -> Self
     where
-        A: ToSocketAddrs,
-        T: Session,
+        F: FnOnce(Arc<Listening>) + Send + 'static,
     {
-        // CLIENTS HANDLING
-        // Fail if can't bind, safe to unwrap
-        let server = Server::bind(addr).unwrap();
-
-        for connection in server.filter_map(Result::ok) {
-            let suite = suite.clone();
-            thread::spawn(move || {
-                let client = connection.accept().unwrap();
-                client.set_nonblocking(true).expect(
-                    "can't use non-blocking webosckets",
-                );
-                debug!("Connection from {}", client.who());
-                super::process_session(suite.as_ref(), client);
-            });
+        let listening = Arc::new(Listening {
+            shutdown: AtomicBool::new(false),
+            sessions: AtomicUsize::new(0),
+        });
+        let shared = listening.clone();
+        let thread = thread::spawn(move || serve(shared));
+        ServerHandle {
+            listening: listening,
+            addr: addr,
+            thread: thread,
         }
     }
-}
 
-#[cfg(feature = "iomould")]
-pub mod iomould {
-    use std::sync::Arc;
-    use std::io::{self, Read, Write, BufRead, BufReader, BufWriter};
-    use session::Session;
-    use flow::{self, Flow};
+    /// Address the server listens on, a server bound to port `0` has
+    /// got a free port.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
 
-    impl From<io::Error> for flow::Error {
-        fn from(_: io::Error) -> Self {
-            flow::Error::ConnectionBroken
-        }
+    /// Stops taking new connections, running sessions go on.
+    pub fn shutdown(&self) {
+        self.listening.shutdown.store(true, Ordering::SeqCst);
     }
 
+    /// Waits until the accept loop exits.
+    pub fn join(self) {
+        if self.thread.join().is_err() {
+            warn!("Accept loop panicked");
+        }
+    }
 
-    pub struct IoFlow<R: Read, W: Write> {
-        who: String,
-        reader: BufReader<R>,
-        writer: BufWriter<W>,
+    /// Number of running sessions.
+    pub fn sessions(&self) -> usize {
+        self.listening.sessions.load(Ordering::SeqCst)
     }
 
-    // Can read from stdin, files, sockets, etc!
-    // It's simpler to implemet async reactor with this flow
-    impl<R: Read, W: Write> IoFlow<R, W> {
-        pub fn new(who: &str, reader: R, writer: W) -> Self {
-            IoFlow {
-                who: who.to_owned(),
-                reader: BufReader::new(reader),
-                writer: BufWriter::new(writer),
+    /// Waits for running sessions to end. Returns `false` on timeout.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.sessions() > 0 {
+            if Instant::now() >= deadline {
+                return false;
             }
+            thread::sleep(Duration::from_millis(50));
         }
+        true
     }
+}
 
-    impl IoFlow<io::Stdin, io::Stdout> {
-        pub fn stdio() -> Self {
-            IoFlow::new("STDIO", io::stdin(), io::stdout())
+pub fn process_session<T, R>(suite: &Suite<T>, rut: R)
+where
+    T: Session,
+    R: Flow,
+{
+    let mut session = match SessionLoop::new(suite, rut) {
+        Ok(session) => session,
+        Err(_) => return,
+    };
+    loop {
+        match session.turn(true) {
+            Turn::Progressed => (),
+            Turn::Idle => thread::sleep(Duration::from_millis(10)),
+            Turn::Ended => break,
         }
     }
+}
 
+/// What a turn of a session loop has done.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Turn {
+    Progressed,
+    /// Nothing to do until the client or a task sends something
+    Idle,
+    Ended,
+}
 
-    impl<R: Read, W: Write> Flow for IoFlow<R, W> {
-        fn who(&self) -> String {
-            self.who.clone()
+/// Last id given to a session.
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Session driven turn by turn. `process_session` turns one on its own
+/// thread, a driver can turn many sessions on a few threads.
+pub struct SessionLoop<'a, T: Session, R: Flow> {
+    suite: &'a Suite<T>,
+    session: Context<T, R>,
+    who: String,
+    recorder: Option<Recorder>,
+    /// Requests of background tasks and when they've begun
+    running: HashMap<TaskId, (String, String, Instant)>,
+    limiter: Option<RateLimiter>,
+    /// Whether the client has logged in or needn't
+    authenticated: bool,
+    /// Token the session is saved under when the client has gone
+    token: Option<String>,
+    /// When the client has sent something or a task has run last time
+    active: Instant,
+    transaction: bool,
+    closed: bool,
+    /// Span every log line of the session is made in
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Token bucket of requests of a session.
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: f64) -> Self {
+        RateLimiter {
+            rate: rate,
+            tokens: rate,
+            updated: Instant::now(),
         }
+    }
 
-        fn pull(&mut self) -> Result<Option<String>, flow::Error> {
-            let mut buf = String::new();
-            let read = self.reader.read_line(&mut buf)?;
-            if read > 0 { Ok(Some(buf)) } else { Ok(None) }
+    /// Takes a token if there is one.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now - self.updated;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
         }
+    }
+}
 
-        fn push(&mut self, content: String) -> Result<(), flow::Error> {
-            self.writer.write_all(content.as_bytes())?;
-            self.writer.write_all(&['\n' as u8])?;
-            self.writer.flush().map_err(flow::Error::from)
+impl<'a, T: Session, R: Flow> SessionLoop<'a, T, R> {
+    /// Builds a session for the flow. If the builder rejects the client
+    /// the flow is closed.
+    pub fn new(suite: &'a Suite<T>, mut rut: R) -> ::std::result::Result<Self, BuildError> {
+        let id = SESSIONS.fetch_add(1, Ordering::SeqCst) + 1;
+        let info = rut.info().with_session_id(id);
+        let who = info.who().to_owned();
+
+        let resumed = match (suite.store.as_ref(), resume::presented(&info)) {
+            (Some(store), Some(token)) => store.restore(token),
+            _ => None,
+        };
+        let built = match resumed {
+            Some(user) => {
+                debug!("Resume a session of {}", who);
+                Ok(user)
+            }
+            None => suite.builder.build(&info),
+        };
+        let user = match built {
+            Ok(user) => user,
+            Err(err) => {
+                debug!("Reject session with {}: {}", who, err);
+                rut.close(REJECT_CODE, &err.to_string()).ok();
+                return Err(err);
+            }
+        };
+        Ok(SessionLoop::start(suite, rut, id, user))
+    }
+
+    /// Serves the flow with a session made elsewhere (by a test), the
+    /// builder of the suite isn't called.
+    pub fn with_session(suite: &'a Suite<T>, rut: R, user: T) -> Self {
+        let id = SESSIONS.fetch_add(1, Ordering::SeqCst) + 1;
+        SessionLoop::start(suite, rut, id, user)
+    }
+
+    fn start(suite: &'a Suite<T>, rut: R, id: usize, user: T) -> Self {
+        let info = rut.info().with_session_id(id);
+        let who = info.who().to_owned();
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("session", id = id, who = who.as_str());
+        #[cfg(feature = "tracing")]
+        let scope = span.clone();
+        #[cfg(feature = "tracing")]
+        let _entered = scope.enter();
+        debug!("Start session {} with {}", id, who);
+        if let Some(ref metrics) = suite.metrics {
+            metrics.session_opened(&info);
+        }
+
+        let mut session: Context<T, R> = Context::new(rut, user);
+        session.set_session_id(id);
+        session.set_ndjson(suite.ndjson);
+        if let Some(ref codec) = suite.codec {
+            session.set_codec(codec.clone());
+        }
+        if !suite.detected.is_empty() {
+            session.set_codec_detection(suite.detected.clone());
+        }
+        session.set_batching(suite.batching.0, suite.batching.1);
+        if let Some(ref pool) = suite.pool {
+            session.set_pool(pool.clone());
+        }
+        if let Some(grace) = suite.cancel_grace {
+            session.set_cancel_grace(grace);
+        }
+        if let Some(timeout) = suite.task_timeout {
+            session.set_task_timeout(timeout);
+        }
+        if let Some(wait) = suite.page_wait {
+            session.set_page_wait(wait);
+        }
+        if let Some(capacity) = suite.output_capacity {
+            session.set_output_capacity(capacity);
+        }
+        let handle = session.handle();
+        session.attach(handle);
+        let token = suite.store.as_ref().map(|_| resume::token(id));
+        if let Some(ref token) = token {
+            let out = Output::new(0, TaskResult::Resume { token: token.clone() });
+            if let Err(err) = session.send(out) {
+                debug!("Can't send a resume token to {}: {}", who, err);
+            }
+        }
+        let recorder = suite.audit_log.as_ref().map(|log| log.recorder(&who));
+        SessionLoop {
+            suite: suite,
+            session: session,
+            who: who,
+            recorder: recorder,
+            running: HashMap::new(),
+            limiter: suite.rate_limit.map(RateLimiter::new),
+            authenticated: suite.authenticator.is_none(),
+            token: token,
+            active: Instant::now(),
+            transaction: false,
+            closed: false,
+            #[cfg(feature = "tracing")]
+            span: span,
         }
     }
 
-    pub fn start<T>(suite: Arc<super::Suite<T>>)
-    where
-        T: Session,
-    {
-        let client = IoFlow::stdio();
-        // Use Arc to allow joining diferent start functions
-        debug!("Connection from {}", client.who());
-        super::process_session(suite.as_ref(), client);
+    /// Delivers outputs of background tasks and takes one message of the
+    /// client. With `wait` it blocks for the message if no tasks run.
+    pub fn turn(&mut self, wait: bool) -> Turn {
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let mut progressed = false;
+        let mut drained = 0;
+        while drained < DRAIN_LIMIT {
+            let mut batch = self.session.collect_batch();
+            if batch.is_empty() {
+                break;
+            }
+            progressed = true;
+            drained += batch.len();
+            for output in &mut batch {
+                let request = match output.result {
+                    // A notification has id `0` as a request may have
+                    TaskResult::Notify(_) => None,
+                    _ => self.running.get(&output.id),
+                };
+                if let Some(&(ref service, ref action, begun)) = request {
+                    output.service = Some(service.clone());
+                    output.action = Some(action.clone());
+                    audit(self.suite, self.recorder.as_ref(), service, action, output);
+                    if let (true, Some(metrics)) = (output.result.is_terminal(), self.suite.metrics.as_ref()) {
+                        metrics.task_completed(service, action, begun.elapsed(), &output.result);
+                    }
+                }
+                self.suite.after(&mut self.session, output);
+                if output.result.is_terminal() {
+                    self.running.remove(&output.id);
+                }
+            }
+            if let Err(err) = self.session.send_batch(&batch) {
+                debug!("Can't deliver outputs to {}: {}", self.who, err);
+                return Turn::Ended;
+            }
+        }
+        if progressed {
+            let waited = self.session.take_output_wait();
+            if let Some(ref metrics) = self.suite.metrics {
+                metrics.outputs_delivered(self.session.session_id(), self.session.pending_outputs(), waited);
+            }
+        }
+        if self.closed && !self.session.is_busy() {
+            return Turn::Ended;
+        }
+        // Outputs of the running tasks are delivered after the client closed
+        let inbound = if self.closed {
+            Ok(None)
+        } else if wait && !self.session.is_busy() && !self.session.is_notified() && self.suite.idle_timeout.is_none() {
+            self.session.recv().map(Some)
+        } else {
+            self.session.try_recv()
+        };
+        if let Ok(Some(ref received)) = inbound {
+            self.active = Instant::now();
+            if let (&Inbound::Request(ref input), Some(metrics)) = (received, self.suite.metrics.as_ref()) {
+                metrics.request_received(&input.service, &input.action);
+            }
+        }
+        let proceed = match inbound {
+            Ok(Some(Inbound::Request(input))) if !self.limiter.as_mut().map_or(true, RateLimiter::take) => {
+                let request = Some((input.service.as_str(), input.action.as_str()));
+                recover(self.suite, &mut self.session, &self.who, self.recorder.as_ref(), input.id, request, Error::RateLimited)
+            }
+            Ok(Some(Inbound::Request(input))) if input.service == AUTH_SERVICE || !self.authenticated => {
+                self.login(input)
+            }
+            Ok(Some(Inbound::Request(input))) => {
+                start(
+                    self.suite,
+                    &mut self.session,
+                    &self.who,
+                    self.recorder.as_ref(),
+                    &mut self.running,
+                    &mut self.transaction,
+                    input,
+                )
+            }
+            Ok(Some(Inbound::Cancel(id))) => {
+                if !self.session.cancel(id) {
+                    debug!("No task {} of {} to cancel", id, self.who);
+                }
+                true
+            }
+            Ok(Some(Inbound::Next(id))) => {
+                if !self.session.next(id) {
+                    debug!("No task {} of {} to page", id, self.who);
+                }
+                true
+            }
+            Ok(None) => {
+                if self.session.is_busy() {
+                    self.active = Instant::now();
+                } else if self.suite.idle_timeout.map_or(false, |timeout| self.active.elapsed() >= timeout) {
+                    debug!("Close idle session with {}", self.who);
+                    self.session.close(IDLE_CODE, "idle timeout").ok();
+                    return Turn::Ended;
+                }
+                return if progressed { Turn::Progressed } else { Turn::Idle };
+            }
+            Err(session::Error::ConnectionClosed) => {
+                self.closed = true;
+                true
+            }
+            Err(reason) => {
+                let id = match reason {
+                    session::Error::Malformed(id, _) => id,
+                    _ => 0,
+                };
+                recover(self.suite, &mut self.session, &self.who, self.recorder.as_ref(), id, None, reason.into())
+            }
+        };
+        if proceed { Turn::Progressed } else { Turn::Ended }
+    }
+}
+
+impl<'a, T: Session, R: Flow> SessionLoop<'a, T, R> {
+    /// Performs an `AUTH_SERVICE` request, denies any other one.
+    fn login(&mut self, input: Input) -> bool {
+        let request = Some((input.service.as_str(), input.action.as_str()));
+        let authenticator = match self.suite.authenticator {
+            Some(ref authenticator) => authenticator,
+            None => {
+                return recover(self.suite, &mut self.session, &self.who, self.recorder.as_ref(), input.id, request, Error::ServiceNotFound);
+            }
+        };
+        if input.service != AUTH_SERVICE {
+            return recover(self.suite, &mut self.session, &self.who, self.recorder.as_ref(), input.id, request, Error::Unauthenticated);
+        }
+        if let Err(err) = authenticator(&input, &mut self.session) {
+            return recover(self.suite, &mut self.session, &self.who, self.recorder.as_ref(), input.id, request, err.into());
+        }
+        debug!("Client {} has logged in", self.who);
+        self.authenticated = true;
+        let output = Output::new(input.id, TaskResult::Done).echo(&input.service, &input.action);
+        match reply(self.suite, &mut self.session, output) {
+            Ok(()) => true,
+            Err(err) => recover(self.suite, &mut self.session, &self.who, self.recorder.as_ref(), input.id, request, err.into()),
+        }
+    }
+}
+
+impl<'a, T: Session, R: Flow> Drop for SessionLoop<'a, T, R> {
+    fn drop(&mut self) {
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        if self.transaction {
+            debug!("Rollback an open transaction of {}", self.who);
+            if let Some(ref hooks) = self.suite.transactions {
+                if let Err(err) = (hooks.rollback)(&mut self.session) {
+                    warn!("Can't rollback a transaction of {}: {}", self.who, err);
+                }
+            }
+        }
+        debug!("Ends session with {} ({:?})", self.who, self.session.stats());
+        if let Some(ref metrics) = self.suite.metrics {
+            metrics.session_closed(self.session.session_id(), self.session.stats());
+        }
+        if let (Some(store), Some(token)) = (self.suite.store.as_ref(), self.token.take()) {
+            if let Some(user) = self.session.take_session() {
+                store.save(token, user);
+            }
+        }
+    }
+}
+
+fn audit<T: Session>(
+    suite: &Suite<T>,
+    recorder: Option<&Recorder>,
+    service: &str,
+    action: &str,
+    output: &Output,
+) {
+    if let Some(recorder) = recorder {
+        if suite.is_audited(service) {
+            recorder.output(service, action, output);
+        }
+    }
+}
+
+/// Begins a request, its task is spawned if it has to run in background.
+/// Returns `false` if the session can't go on.
+fn start<T: Session, R: Flow>(
+    suite: &Suite<T>,
+    session: &mut Context<T, R>,
+    who: &str,
+    recorder: Option<&Recorder>,
+    running: &mut HashMap<TaskId, (String, String, Instant)>,
+    transaction: &mut bool,
+    input: Input,
+) -> bool {
+    // The running task keeps the id, outputs of both would be mixed
+    if session.is_running(input.id) {
+        let request = Some((input.service.as_str(), input.action.as_str()));
+        return recover(suite, session, who, recorder, input.id, request, Error::DuplicateId);
+    }
+    if let Err(reason) = suite.before(session, &input) {
+        let request = Some((input.service.as_str(), input.action.as_str()));
+        return recover(suite, session, who, recorder, input.id, request, reason.into());
+    }
+    let Input { id, service, action, payload, timeout_ms } = input;
+    debug!("Begin new request processing for {}", who);
+    let begun = Instant::now();
+    let timeout = timeout_ms.map(Duration::from_millis);
+    let result = perform(suite, session, recorder, transaction, id, &service, &action, payload, timeout);
+    match result {
+        Ok(Some((task, limit))) => {
+            session.spawn(id, task);
+            if let Some(limit) = limit {
+                session.limit(id, limit);
+            }
+            running.insert(id, (service, action, begun));
+            true
+        }
+        Ok(None) => {
+            if let Some(ref metrics) = suite.metrics {
+                metrics.task_completed(&service, &action, begun.elapsed(), &TaskResult::Done);
+            }
+            true
+        }
+        Err(reason) => {
+            if let Some(ref metrics) = suite.metrics {
+                metrics.task_completed(&service, &action, begun.elapsed(), &outcome(&reason));
+            }
+            let request = Some((service.as_str(), action.as_str()));
+            recover(suite, session, who, recorder, id, request, reason)
+        }
+    }
+}
+
+/// Terminal result of a failed request as the client gets it.
+fn outcome(reason: &Error) -> TaskResult {
+    match *reason {
+        Error::SessionFailed(session::Error::Canceled) => TaskResult::Cancelled,
+        Error::WorkerFailed(worker::Error::Redirect(ref url)) => TaskResult::Redirect { url: url.clone() },
+        _ => TaskResult::Fail(reason.failure()),
+    }
+}
+
+fn perform<T: Session, R: Flow>(
+    suite: &Suite<T>,
+    session: &mut Context<T, R>,
+    recorder: Option<&Recorder>,
+    transaction: &mut bool,
+    id: TaskId,
+    service: &str,
+    action: &str,
+    payload: Value,
+    timeout: Option<Duration>,
+) -> Result<Option<(Box<Task>, Option<Duration>)>> {
+    if service == TX_SERVICE {
+        suite.transact(session, action, transaction)?;
+        reply(suite, session, Output::new(id, TaskResult::Done).echo(service, action))?;
+        return Ok(None);
+    }
+    if service == CAN_SERVICE {
+        let query = serde_json::from_value(payload).map_err(session::Error::from)?;
+        let answer = suite.can(session, &query);
+        let output = serde_json::to_value(answer).map_err(session::Error::from)?;
+        reply(suite, session, Output::new(id, TaskResult::Item(output)).echo(service, action))?;
+        reply(suite, session, Output::new(id, TaskResult::Done).echo(service, action))?;
+        return Ok(None);
+    }
+    if service == INTROSPECT_SERVICE {
+        let visible = suite.can_guard.as_ref().map_or(true, |guard| guard(session));
+        let services = if visible { suite.introspect() } else { BTreeMap::new() };
+        let output = serde_json::to_value(services).map_err(session::Error::from)?;
+        reply(suite, session, Output::new(id, TaskResult::Item(output)).echo(service, action))?;
+        reply(suite, session, Output::new(id, TaskResult::Done).echo(service, action))?;
+        return Ok(None);
+    }
+    let payload = match suite.input_transform {
+        Some(ref transform) => transform(payload).map_err(Error::InputRejected)?,
+        None => payload,
+    };
+    if let Some(recorder) = recorder {
+        if suite.is_audited(service) {
+            recorder.request(id, service, action, &payload);
+        }
+    }
+    if let Some((budget, period)) = suite.compute_budget {
+        if session.period_blocking_time(period) > budget {
+            return Err(Error::OverBudget);
+        }
+    }
+    let mut worker = suite
+        .services
+        .get(service)
+        .ok_or(Error::ServiceNotFound)?
+        .route_with(action, session)?;
+    if let (None, Some(limit)) = (worker.max_output_bytes, suite.max_output_bytes) {
+        worker = worker.max_output_bytes(limit);
+    }
+    if let Some(ref requires) = worker.requires {
+        requires(session).map_err(worker::Error::from)?;
+    }
+    // The action overrides the suite, the client can only shorten it
+    let limit = match (worker.timeout.or(suite.task_timeout), timeout) {
+        (Some(server), Some(client)) => Some(server.min(client)),
+        (server, client) => server.or(client),
+    };
+    if limit.is_some() && !worker.background {
+        debug!("Request {} runs untimed, its action isn't in background", id);
+    }
+    let mut sent = Vec::new();
+    let outcome = {
+        let mut observer = |output: &Output| {
+            audit(suite, recorder, service, action, output);
+            if !suite.middlewares.is_empty() {
+                sent.push(output.clone());
+            }
+        };
+        session.perform(id, (service, action), &mut worker, payload, &mut observer)
+    };
+    for output in &sent {
+        suite.after(session, output);
+    }
+    let task = outcome??;
+    Ok(task.map(|task| (task, limit)))
+}
+
+/// Sends an output of a reserved service.
+fn reply<T: Session, R: Flow>(suite: &Suite<T>, session: &mut Context<T, R>, output: Output) -> session::Result<()> {
+    suite.after(session, &output);
+    session.send(output)
+}
+
+/// Informs the client if a request failed, `fail` is its terminal message.
+/// Returns `false` if the session can't go on.
+fn recover<T: Session, R: Flow>(
+    suite: &Suite<T>,
+    session: &mut Context<T, R>,
+    who: &str,
+    recorder: Option<&Recorder>,
+    id: TaskId,
+    request: Option<(&str, &str)>,
+    reason: Error,
+) -> bool {
+    match reason {
+        Error::SessionFailed(session::Error::Canceled) |
+        Error::SessionFailed(session::Error::ConnectionClosed) |
+        Error::WorkerFailed(worker::Error::Redirect(_)) |
+        Error::WorkerFailed(worker::Error::CloseSession { .. }) => (),
+        _ => {
+            let context = ErrorContext {
+                who: who,
+                id: id,
+                service: request.map(|r| r.0),
+                action: request.map(|r| r.1),
+            };
+            suite.report_error(&reason, &context);
+        }
+    }
+    let mut ends = false;
+    let result = match reason {
+        Error::SessionFailed(session::Error::Canceled) => return true,
+        Error::WorkerFailed(worker::Error::Redirect(ref url)) => {
+            debug!("Redirect {} to {}", who, url);
+            if let Err(err) = session.redirect(id, url) {
+                warn!("Can't redirect {}: {}", who, err);
+            }
+            return false;
+        }
+        Error::WorkerFailed(worker::Error::CloseSession { code, ref reason }) => {
+            debug!("Close session with {}: {} {}", who, code, reason);
+            if let Err(err) = session.close(code, reason) {
+                warn!("Can't close session with {}: {}", who, err);
+            }
+            return false;
+        }
+        // The bad frame was already skipped, the flow is usable
+        Error::SessionFailed(session::Error::FlowBroken(flow::Error::LineTooLong)) |
+        Error::SessionFailed(session::Error::FlowBroken(flow::Error::MessageTooLarge)) => {
+            ends = suite.close_on_oversized;
+            TaskResult::Fail(reason.failure())
+        }
+        Error::SessionFailed(session::Error::FlowBroken(flow::Error::BadMessageEncoding)) => {
+            TaskResult::Fail(reason.failure())
+        }
+        Error::SessionFailed(session::Error::FlowBroken(_)) => return false,
+        Error::SessionFailed(session::Error::ConnectionClosed) => return false,
+        // A denial is an answer to the request, not a fault
+        Error::WorkerFailed(worker::Error::PermissionWrong(ref cause)) => {
+            debug!("Request {} of {} denied: {}", id, who, cause);
+            TaskResult::Fail(reason.failure())
+        }
+        Error::RateLimited => {
+            debug!("Request {} of {} over the rate limit", id, who);
+            TaskResult::Fail(reason.failure())
+        }
+        Error::DuplicateId => {
+            debug!("Request {} of {} is already running", id, who);
+            TaskResult::Fail(reason.failure())
+        }
+        Error::Unauthenticated => {
+            debug!("Request {} of {} before login", id, who);
+            TaskResult::Fail(reason.failure())
+        }
+        _ => {
+            warn!(
+                "Request processing {} have catch an error {:?}",
+                who,
+                reason
+            );
+            TaskResult::Fail(reason.failure())
+        }
+    };
+    let mut output = Output::new(id, result);
+    if let Some((service, action)) = request {
+        output = output.echo(service, action);
+        audit(suite, recorder, service, action, &output);
+    }
+    suite.after(session, &output);
+    match session.send(output) {
+        Ok(()) => !ends,
+        Err(err) => {
+            debug!("Can't report a failure to {}: {}", who, err);
+            false
+        }
+    }
+}
+
+#[cfg(feature = "wsmould")]
+pub mod wsmould {
+    use std::thread;
+    use std::io::{ErrorKind, Write};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::net::{Shutdown, SocketAddr, ToSocketAddrs, TcpListener, TcpStream};
+    use std::str::Utf8Error;
+    use std::time::{SystemTime, Instant, Duration};
+    use websocket::sync::{Client, Stream};
+    use websocket::sync::server::{IntoWs, Upgrade};
+    use websocket::stream::sync::AsTcpStream;
+    use websocket::message::{OwnedMessage, Message};
+    use websocket::result::{WebSocketError, WebSocketResult};
+    #[cfg(feature = "deflate")]
+    use websocket::dataframe::Opcode;
+    #[cfg(feature = "deflate")]
+    use deflate::{self, Deflate};
+    #[cfg(feature = "wss")]
+    use native_tls::{self, Pkcs12, TlsAcceptor};
+    use session::Session;
+    use flow::{self, Flow, FlowStats, Pull, ConnectionInfo};
+    use pool::Pool;
+    use super::{Listening, SessionGuard, ServerHandle, Driver};
+
+    impl From<WebSocketError> for flow::Error {
+        fn from(_: WebSocketError) -> Self {
+            flow::Error::ConnectionBroken
+        }
+    }
+
+    impl From<Utf8Error> for flow::Error {
+        fn from(_: Utf8Error) -> Self {
+            flow::Error::BadMessageEncoding
+        }
+    }
+
+    /// Keep-alive and handshake settings of websocket connections.
+    #[derive(Debug, Clone)]
+    pub struct WsConfig {
+        /// Silence of a peer after which the server pings it.
+        pub ping_interval: Duration,
+        /// Silence of a peer after which the connection is considered
+        /// dead, pongs count as frames. `None` never drops a peer.
+        pub idle_timeout: Option<Duration>,
+        /// Compresses messages of clients which offer permessage-deflate,
+        /// others get plain frames.
+        #[cfg(feature = "deflate")]
+        pub deflate: bool,
+        /// Messages shorter than it are sent plain even if compression
+        /// is negotiated.
+        #[cfg(feature = "deflate")]
+        pub min_compressed_bytes: usize,
+        /// Origins of browser pages which may connect, e.g.
+        /// `https://app.example.com`. Clients which send no `Origin`
+        /// aren't browsers and pass. Empty allows any origin.
+        pub allowed_origins: Vec<String>,
+        /// Time a client has for the TLS and websocket handshakes since
+        /// its connection is accepted, a slower one is dropped.
+        pub handshake_timeout: Duration,
+        /// Threads which make handshakes, connections wait for a free one.
+        /// It mustn't be zero.
+        pub handshake_threads: usize,
+    }
+
+    impl Default for WsConfig {
+        fn default() -> Self {
+            WsConfig {
+                ping_interval: Duration::from_secs(20),
+                idle_timeout: None,
+                #[cfg(feature = "deflate")]
+                deflate: false,
+                #[cfg(feature = "deflate")]
+                min_compressed_bytes: deflate::MIN_COMPRESSED,
+                allowed_origins: Vec::new(),
+                handshake_timeout: Duration::from_secs(10),
+                handshake_threads: 4,
+            }
+        }
+    }
+
+    /// Upgrade request of a connection: its URI, headers and
+    /// the chosen subprotocol.
+    struct Handshake(String, Vec<(String, String)>, Option<String>);
+
+    /// Websocket flow over plain TCP or TLS (`S` is a `TlsStream`).
+    pub struct WsFlow<S: Stream + AsTcpStream = TcpStream> {
+        client: Client<S>,
+        handshake: Option<Handshake>,
+        stats: FlowStats,
+        close_timeout: Duration,
+        max_message: Option<usize>,
+        config: WsConfig,
+        /// Address of the peer taken while the socket was alive
+        peer_addr: Option<SocketAddr>,
+        last_ping: SystemTime,
+        last_seen: Instant,
+        #[cfg(feature = "deflate")]
+        deflate: Option<Deflate>,
+    }
+
+    impl<S: Stream + AsTcpStream> WsFlow<S> {
+        pub fn new(client: Client<S>) -> Self {
+            WsFlow::with_config(client, WsConfig::default())
+        }
+
+        pub fn with_config(client: Client<S>, config: WsConfig) -> Self {
+            WsFlow {
+                peer_addr: client.peer_addr().ok(),
+                client: client,
+                handshake: None,
+                stats: FlowStats::default(),
+                close_timeout: Duration::from_secs(3),
+                max_message: None,
+                config: config,
+                last_ping: SystemTime::now(),
+                last_seen: Instant::now(),
+                #[cfg(feature = "deflate")]
+                deflate: None,
+            }
+        }
+
+        /// Sets how long `close` waits for a close frame of the peer
+        /// before the socket is dropped.
+        pub fn close_timeout(mut self, timeout: Duration) -> Self {
+            self.close_timeout = timeout;
+            self
+        }
+
+        /// Rejects messages bigger than `limit` bytes with
+        /// `MessageTooLarge`. A message is read whole before it's checked.
+        pub fn max_message_bytes(mut self, limit: usize) -> Self {
+            self.max_message = Some(limit);
+            self
+        }
+
+        fn check_size(&self, size: usize) -> Result<(), flow::Error> {
+            match self.max_message {
+                Some(limit) if size > limit => {
+                    debug!("Message of {} bytes from {} rejected", size, self.who());
+                    Err(flow::Error::MessageTooLarge)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn send(&mut self, message: &Message) -> Result<(), flow::Error> {
+            self.client.send_message(message)?;
+            self.stats.frames_sent += 1;
+            self.stats.bytes_sent += message.payload.len() as u64;
+            Ok(())
+        }
+
+        #[cfg(feature = "deflate")]
+        fn send_deflated(&mut self, opcode: Opcode, content: &[u8]) -> Result<(), flow::Error> {
+            let frame = Deflate::frame(opcode, content, self.config.min_compressed_bytes);
+            self.client.send_dataframe(&frame)?;
+            self.stats.frames_sent += 1;
+            self.stats.bytes_sent += content.len() as u64;
+            Ok(())
+        }
+
+        fn recv(&mut self) -> WebSocketResult<OwnedMessage> {
+            #[cfg(feature = "deflate")]
+            {
+                if let Some(ref mut deflate) = self.deflate {
+                    loop {
+                        let frame = self.client.recv_dataframe()?;
+                        if let Some(message) = deflate.accept(frame, self.max_message)? {
+                            return Ok(message);
+                        }
+                    }
+                }
+            }
+            self.client.recv_message()
+        }
+    }
+
+    impl<S: Stream + AsTcpStream> Flow for WsFlow<S> {
+        fn who(&self) -> String {
+            match self.peer_addr {
+                Some(ip) => format!("WS IP {}", ip),
+                None => "WS IP <unknown>".to_owned(),
+            }
+        }
+
+        fn info(&self) -> ConnectionInfo {
+            let info = ConnectionInfo::new(self.who(), self.peer_addr);
+            match self.handshake {
+                Some(Handshake(ref uri, ref headers, ref protocol)) => {
+                    info.handshake(uri.clone(), headers.clone(), protocol.clone())
+                }
+                None => info,
+            }
+        }
+
+        fn pull(&mut self) -> Result<Option<String>, flow::Error> {
+            loop {
+                match self.try_pull()? {
+                    Pull::Message(content) => return Ok(Some(content)),
+                    Pull::Closed => return Ok(None),
+                    Pull::Empty => thread::sleep(Duration::from_millis(50)),
+                    Pull::Binary(_) => debug!("Binary frame of {} skipped", self.who()),
+                }
+            }
+        }
+
+        fn try_pull(&mut self) -> Result<Pull, flow::Error> {
+            let message = self.recv();
+            match message {
+                Ok(message) => {
+                    self.stats.frames_received += 1;
+                    // No need ping if interaction was successful
+                    self.last_ping = SystemTime::now();
+                    self.last_seen = Instant::now();
+                    match message {
+                        OwnedMessage::Text(content) => {
+                            self.stats.bytes_received += content.len() as u64;
+                            self.check_size(content.len())?;
+                            return Ok(Pull::Message(content));
+                        }
+                        OwnedMessage::Close(_) => {
+                            return Ok(Pull::Closed);
+                        }
+                        OwnedMessage::Ping(payload) => {
+                            self.stats.bytes_received += payload.len() as u64;
+                            self.send(&Message::pong(payload))?;
+                        }
+                        OwnedMessage::Pong(payload) => {
+                            self.stats.bytes_received += payload.len() as u64;
+                            trace!("pong received: {:?}", payload);
+                        }
+                        OwnedMessage::Binary(payload) => {
+                            self.stats.bytes_received += payload.len() as u64;
+                            self.check_size(payload.len())?;
+                            return Ok(Pull::Binary(payload));
+                        }
+                    }
+                }
+                Err(WebSocketError::IoError(ref err))
+                    if err.kind() == ErrorKind::WouldBlock => {
+                    if let Some(timeout) = self.config.idle_timeout {
+                        if self.last_seen.elapsed() > timeout {
+                            debug!("No frames from {} for {:?}", self.who(), timeout);
+                            return Err(flow::Error::ConnectionBroken);
+                        }
+                    }
+                    let ping_interval = self.config.ping_interval;
+                    let elapsed = self.last_ping
+                        .elapsed()
+                        .map(|dur| dur > ping_interval)
+                        .unwrap_or(false);
+                    if elapsed {
+                        // Reset time to stop ping flood
+                        self.last_ping = SystemTime::now();
+                        trace!("sending ping");
+                        self.send(&Message::ping("mould-ping".as_bytes()))?;
+                        self.stats.pings_sent += 1;
+                    }
+                }
+                #[cfg(feature = "deflate")]
+                Err(WebSocketError::DataFrameError(deflate::TOO_LARGE)) => {
+                    debug!("Inflated message from {} rejected", self.who());
+                    return Err(flow::Error::MessageTooLarge);
+                }
+                Err(err) => {
+                    return Err(flow::Error::from(err));
+                }
+            }
+            Ok(Pull::Empty)
+        }
+
+        fn push(&mut self, content: String) -> Result<(), flow::Error> {
+            #[cfg(feature = "deflate")]
+            {
+                if self.deflate.is_some() {
+                    return self.send_deflated(Opcode::Text, content.as_bytes());
+                }
+            }
+            self.send(&Message::text(content))
+        }
+
+        fn push_binary(&mut self, content: Vec<u8>) -> Result<(), flow::Error> {
+            #[cfg(feature = "deflate")]
+            {
+                if self.deflate.is_some() {
+                    return self.send_deflated(Opcode::Binary, &content);
+                }
+            }
+            self.send(&Message::binary(content))
+        }
+
+        fn stats(&self) -> FlowStats {
+            self.stats
+        }
+
+        fn close(&mut self, code: u16, reason: &str) -> Result<(), flow::Error> {
+            self.send(&Message::close_because(code, reason))?;
+            // Never hang on a dead peer: wait for its close frame in bounds
+            self.client.set_nonblocking(true).ok();
+            let deadline = Instant::now() + self.close_timeout;
+            loop {
+                match self.client.recv_message() {
+                    Ok(OwnedMessage::Close(_)) => break,
+                    Ok(_) => (),
+                    Err(WebSocketError::IoError(ref err))
+                        if err.kind() == ErrorKind::WouldBlock => {
+                        if Instant::now() >= deadline {
+                            debug!("Close handshake with {} timed out", self.who());
+                            break;
+                        }
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(_) => break,
+                }
+            }
+            // The peer could drop the connection first
+            self.client.shutdown().ok();
+            Ok(())
+        }
+    }
+
+
+
+    /// Serves clients until the process ends.
+    pub fn start<T, A>(addr: A, suite: Arc<super::Suite<T>>)
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        spawn(addr, suite).join();
+    }
+
+    pub fn start_with<T, A>(addr: A, suite: Arc<super::Suite<T>>, config: WsConfig)
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        spawn_with(addr, suite, config).join();
+    }
+
+    /// Serves clients in background, the handle stops it.
+    pub fn spawn<T, A>(addr: A, suite: Arc<super::Suite<T>>) -> ServerHandle
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        spawn_with(addr, suite, WsConfig::default())
+    }
+
+    pub fn spawn_with<T, A>(addr: A, suite: Arc<super::Suite<T>>, config: WsConfig) -> ServerHandle
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        assert!(config.handshake_threads > 0, "handshakes need at least one thread");
+        // CLIENTS HANDLING
+        // Fail if can't bind, safe to unwrap
+        let listener = TcpListener::bind(addr).unwrap();
+        // Lets the loop check shutdown between connections
+        listener.set_nonblocking(true).expect("can't use non-blocking listener");
+        let local = listener.local_addr().expect("listener has an address");
+        let busy = Some(BUSY_RESPONSE);
+        ServerHandle::spawn(local, move |listening| serve(listener, Some, busy, suite, config, listening))
+    }
+
+    /// Serves `wss://` clients. The TLS handshake is made before
+    /// the websocket one.
+    #[cfg(feature = "wss")]
+    pub fn start_tls<T, A>(addr: A, suite: Arc<super::Suite<T>>, acceptor: TlsAcceptor)
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        spawn_tls(addr, suite, acceptor).join();
+    }
+
+    #[cfg(feature = "wss")]
+    pub fn spawn_tls<T, A>(
+        addr: A,
+        suite: Arc<super::Suite<T>>,
+        acceptor: TlsAcceptor,
+    ) -> ServerHandle
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        spawn_tls_with(addr, suite, acceptor, WsConfig::default())
+    }
+
+    #[cfg(feature = "wss")]
+    pub fn spawn_tls_with<T, A>(
+        addr: A,
+        suite: Arc<super::Suite<T>>,
+        acceptor: TlsAcceptor,
+        config: WsConfig,
+    ) -> ServerHandle
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        assert!(config.handshake_threads > 0, "handshakes need at least one thread");
+        // Fail if can't bind, safe to unwrap
+        let listener = TcpListener::bind(addr).unwrap();
+        listener.set_nonblocking(true).expect("can't use non-blocking listener");
+        let secure = move |stream: TcpStream| match acceptor.accept(stream) {
+            Ok(stream) => Some(stream),
+            Err(err) => {
+                debug!("TLS handshake failed: {}", err);
+                None
+            }
+        };
+        let local = listener.local_addr().expect("listener has an address");
+        // A TLS client can't read a plain answer
+        ServerHandle::spawn(local, move |listening| serve(listener, secure, None, suite, config, listening))
+    }
+
+    /// Builds an acceptor from a PKCS#12 archive with a certificate chain
+    /// and its private key. It's made from PEM files with
+    /// `openssl pkcs12 -export -in chain.pem -inkey key.pem -out identity.p12`.
+    #[cfg(feature = "wss")]
+    pub fn tls_acceptor(pkcs12: &[u8], password: &str) -> Result<TlsAcceptor, native_tls::Error> {
+        let identity = Pkcs12::from_der(pkcs12, password)?;
+        TlsAcceptor::builder(identity)?.build()
+    }
+
+    /// Answer to an upgrade request when the server is full.
+    const BUSY_RESPONSE: &'static [u8] = b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+    /// Turns a connection away without its handshakes, so a full server
+    /// spends no thread on it.
+    fn reject(mut stream: TcpStream, busy: Option<&[u8]>) {
+        debug!("Reject a connection, the server is busy");
+        if let Some(busy) = busy {
+            // The accept loop mustn't wait for the client
+            stream.set_nonblocking(true).ok();
+            stream.write_all(busy).ok();
+        }
+        stream.shutdown(Shutdown::Both).ok();
+    }
+
+    /// Connection which is making its handshakes.
+    struct Pending {
+        stream: TcpStream,
+        deadline: Instant,
+        done: Arc<AtomicBool>,
+    }
+
+    /// Accepts connections, their handshakes are made by a pool of
+    /// threads so a slow client doesn't hold up others. The accept loop
+    /// shuts down connections which don't finish them in time. `secure`
+    /// makes the TLS handshake of `wss://` connections, `busy` is sent to
+    /// connections over the limit.
+    fn serve<T, F, S>(
+        listener: TcpListener,
+        secure: F,
+        busy: Option<&'static [u8]>,
+        suite: Arc<super::Suite<T>>,
+        config: WsConfig,
+        listening: Arc<Listening>,
+    ) where
+        T: Session,
+        F: Fn(TcpStream) -> Option<S> + Send + Sync + 'static,
+        S: Stream + AsTcpStream + Send + 'static,
+    {
+        let secure = Arc::new(secure);
+        let handshakes = Pool::new(config.handshake_threads);
+        let mut pending: Vec<Pending> = Vec::new();
+        let mut driver = suite.session_threads.map(|threads| Driver::new(suite.clone(), threads));
+        while !listening.shutdown.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            pending.retain(|handshake| {
+                if handshake.done.load(Ordering::SeqCst) {
+                    false
+                } else if now >= handshake.deadline {
+                    debug!("Drop a connection, its handshake is too slow");
+                    handshake.stream.shutdown(Shutdown::Both).ok();
+                    false
+                } else {
+                    true
+                }
+            });
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                // No pending connections
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+            };
+            let guard = match SessionGuard::enter(&listening, &suite) {
+                Some(guard) => guard,
+                None => {
+                    reject(stream, busy);
+                    continue;
+                }
+            };
+            let watched = match stream.try_clone() {
+                Ok(watched) => watched,
+                Err(err) => {
+                    warn!("Can't watch a handshake: {}", err);
+                    continue;
+                }
+            };
+            let done = Arc::new(AtomicBool::new(false));
+            pending.push(Pending {
+                stream: watched,
+                deadline: now + config.handshake_timeout,
+                done: done.clone(),
+            });
+            let driver = driver.as_mut().map(Driver::sender);
+            let secure = secure.clone();
+            let suite = suite.clone();
+            let config = config.clone();
+            handshakes.execute(move || {
+                let client = handshake(stream, &*secure).and_then(|connection| open(connection, &suite, config));
+                done.store(true, Ordering::SeqCst);
+                let client = match client {
+                    Some(client) => client,
+                    None => return,
+                };
+                match driver {
+                    // The driver thread lives while the server does
+                    Some(driver) => {
+                        driver.send((client, guard)).ok();
+                    }
+                    None => {
+                        thread::spawn(move || {
+                            let _guard = guard;
+                            super::process_session(suite.as_ref(), client);
+                        });
+                    }
+                }
+            });
+        }
+        // Handshakes in progress don't start sessions any more
+        for handshake in pending {
+            handshake.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    /// Reads the upgrade request of a connection after its TLS handshake.
+    fn handshake<F, S>(stream: TcpStream, secure: &F) -> Option<Upgrade<S>>
+    where
+        F: Fn(TcpStream) -> Option<S>,
+        S: Stream + AsTcpStream,
+    {
+        // Some platforms pass non-blocking mode of a listener on
+        if let Err(err) = stream.set_nonblocking(false) {
+            warn!("Can't block on a handshake: {}", err);
+            return None;
+        }
+        let stream = secure(stream)?;
+        match stream.into_ws() {
+            Ok(connection) => Some(connection),
+            Err((_, _, _, err)) => {
+                debug!("Can't read a websocket handshake: {:?}", err);
+                None
+            }
+        }
+    }
+
+    fn is_allowed(allowed: &[String], origin: Option<&str>) -> bool {
+        match origin {
+            Some(origin) if !allowed.is_empty() => {
+                allowed.iter().any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            }
+            _ => true,
+        }
+    }
+
+    fn open<T, S>(connection: Upgrade<S>, suite: &super::Suite<T>, config: WsConfig) -> Option<WsFlow<S>>
+    where
+        T: Session,
+        S: Stream + AsTcpStream,
+    {
+        let uri = connection.request.subject.1.to_string();
+        let headers = connection
+            .request
+            .headers
+            .iter()
+            .map(|header| (header.name().to_owned(), header.value_string()))
+            .collect();
+        if !is_allowed(&config.allowed_origins, connection.origin()) {
+            debug!("Reject a connection from origin {:?}", connection.origin());
+            connection.reject().ok();
+            return None;
+        }
+        // Browsers pass tokens as subprotocols and fail if none is echoed
+        let protocol = connection.protocols().first().cloned();
+        let connection = match protocol {
+            Some(ref protocol) => connection.use_protocol(protocol.as_str()),
+            None => connection,
+        };
+        #[cfg(feature = "deflate")]
+        let (connection, deflate) = match Deflate::negotiate(connection.extensions()) {
+            Some((deflate, response)) if config.deflate => {
+                (connection.use_extension(response), Some(deflate))
+            }
+            _ => (connection, None),
+        };
+        let client = match connection.accept() {
+            Ok(client) => client,
+            Err((_, err)) => {
+                warn!("Can't accept a websocket connection: {}", err);
+                return None;
+            }
+        };
+        // The peer could be gone already
+        if let Err(err) = client.set_nonblocking(true) {
+            warn!("Can't use a non-blocking websocket: {}", err);
+            return None;
+        }
+        let mut client = WsFlow::with_config(client, config);
+        client.handshake = Some(Handshake(uri, headers, protocol));
+        #[cfg(feature = "deflate")]
+        {
+            client.deflate = deflate;
+        }
+        if let Some(limit) = suite.max_message_bytes {
+            client = client.max_message_bytes(limit);
+        }
+        debug!("Connection from {}", client.who());
+        Some(client)
+    }
+}
+
+#[cfg(feature = "iomould")]
+pub mod iomould {
+    use std::thread;
+    use std::sync::Arc;
+    use std::sync::mpsc::{self, Sender, Receiver, TryRecvError};
+    use std::io::{self, Cursor, Read, Write, BufRead, BufReader, BufWriter};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use session::Session;
+    use flow::{self, Flow, FlowStats, Pull, ConnectionInfo};
+
+    impl From<io::Error> for flow::Error {
+        fn from(_: io::Error) -> Self {
+            flow::Error::ConnectionBroken
+        }
+    }
+
+
+    /// A read message and the number of bytes it took.
+    type Line = (Result<Option<String>, flow::Error>, usize);
+
+    /// How messages are delimited in a stream.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Framing {
+        /// A message per line, the newline isn't a part of it
+        Lines,
+        /// A message follows its length in bytes, 4 bytes big-endian,
+        /// so it can span lines
+        LengthPrefixed,
+    }
+
+    /// Where messages of a flow are read.
+    enum Source<R: Read> {
+        /// On the thread which pulls, pulling blocks
+        Inline(BufReader<R>),
+        /// By `read_lines` on a thread of its own, it starts with the first
+        /// pull
+        Threaded(Option<(BufReader<R>, Spawn<R>)>, Option<Receiver<Line>>),
+        /// On the thread which pulls from a non-blocking reader, a message
+        /// is taken when it's whole
+        Polled(R, Partial),
+    }
+
+    /// Bytes of a polled reader which don't make a whole message yet.
+    #[derive(Default)]
+    struct Partial {
+        buf: Vec<u8>,
+        /// Bytes of an overlong message dropped so far, and how many are
+        /// left if the length is known
+        skipped: Option<(usize, Option<usize>)>,
+        ended: bool,
+    }
+
+    impl Partial {
+        /// Reads what has come until a message is whole. `None` if it
+        /// isn't, the reader would block.
+        fn poll<R: Read>(&mut self, reader: &mut R, framing: Framing, max_line: Option<usize>) -> Option<Line> {
+            let mut chunk = [0u8; 4096];
+            loop {
+                let line = match framing {
+                    Framing::Lines => self.take_line(max_line),
+                    Framing::LengthPrefixed => self.take_prefixed(max_line),
+                };
+                if line.is_some() {
+                    return line;
+                }
+                if self.ended {
+                    return Some(self.end(framing, max_line));
+                }
+                match reader.read(&mut chunk) {
+                    Ok(0) => self.ended = true,
+                    Ok(read) => self.buf.extend_from_slice(&chunk[..read]),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                    Err(err) => return Some((Err(err.into()), 0)),
+                }
+            }
+        }
+
+        fn take_line(&mut self, max_line: Option<usize>) -> Option<Line> {
+            let newline = self.buf.iter().position(|b| *b == b'\n');
+            if let Some((skipped, _)) = self.skipped {
+                return match newline {
+                    Some(pos) => {
+                        self.buf.drain(..pos + 1);
+                        self.skipped = None;
+                        Some((Err(flow::Error::LineTooLong), skipped + pos + 1))
+                    }
+                    None => {
+                        self.skipped = Some((skipped + self.buf.len(), None));
+                        self.buf.clear();
+                        None
+                    }
+                };
+            }
+            match newline {
+                Some(pos) => {
+                    let line: Vec<u8> = self.buf.drain(..pos + 1).collect();
+                    Some(read_line(&mut BufReader::new(Cursor::new(line)), max_line))
+                }
+                // Two extra bytes fit the `\r\n` of the longest line
+                None if max_line.map_or(false, |limit| self.buf.len() > limit + 2) => {
+                    self.skipped = Some((self.buf.len(), None));
+                    self.buf.clear();
+                    None
+                }
+                None => None,
+            }
+        }
+
+        fn take_prefixed(&mut self, max_line: Option<usize>) -> Option<Line> {
+            if let Some((skipped, Some(left))) = self.skipped {
+                let dropped = left.min(self.buf.len());
+                self.buf.drain(..dropped);
+                if dropped < left {
+                    self.skipped = Some((skipped + dropped, Some(left - dropped)));
+                    return None;
+                }
+                self.skipped = None;
+                return Some((Err(flow::Error::LineTooLong), skipped + dropped));
+            }
+            if self.buf.len() < 4 {
+                return None;
+            }
+            let length = self.buf[..4].iter().fold(0usize, |length, byte| length << 8 | *byte as usize);
+            if max_line.map_or(false, |limit| length > limit) {
+                self.buf.drain(..4);
+                self.skipped = Some((4, Some(length)));
+                return self.take_prefixed(max_line);
+            }
+            if self.buf.len() < 4 + length {
+                return None;
+            }
+            let message: Vec<u8> = self.buf.drain(..4 + length).collect();
+            Some(read_prefixed(&mut BufReader::new(Cursor::new(message)), max_line))
+        }
+
+        /// What is left when the reader has ended.
+        fn end(&mut self, framing: Framing, max_line: Option<usize>) -> Line {
+            if let Some((skipped, _)) = self.skipped.take() {
+                self.buf.clear();
+                return (Err(flow::Error::LineTooLong), skipped);
+            }
+            if self.buf.is_empty() {
+                return (Ok(None), 0);
+            }
+            let rest = ::std::mem::replace(&mut self.buf, Vec::new());
+            match framing {
+                // The last line may have no newline
+                Framing::Lines => read_line(&mut BufReader::new(Cursor::new(rest)), max_line),
+                Framing::LengthPrefixed => (Err(flow::Error::ConnectionBroken), rest.len()),
+            }
+        }
+    }
+
+    /// Starts a thread which reads messages, only readers which can be
+    /// sent to a thread have it.
+    type Spawn<R> = fn(BufReader<R>, Framing, Option<usize>) -> Receiver<Line>;
+
+    /// Flow over a pair of streams. Pulling waits for a message unless
+    /// messages are read on a separate thread (see `threaded`).
+    pub struct IoFlow<R: Read, W: Write> {
+        who: String,
+        source: Source<R>,
+        writer: BufWriter<W>,
+        framing: Framing,
+        max_line: Option<usize>,
+        peer_addr: Option<SocketAddr>,
+        stats: FlowStats,
+    }
+
+    // Can read from stdin, files, sockets, etc!
+    // It's simpler to implemet async reactor with this flow
+    impl<R: Read, W: Write> IoFlow<R, W> {
+        pub fn new(who: &str, reader: R, writer: W) -> Self {
+            IoFlow {
+                who: who.to_owned(),
+                source: Source::Inline(BufReader::new(reader)),
+                writer: BufWriter::new(writer),
+                framing: Framing::Lines,
+                max_line: None,
+                peer_addr: None,
+                stats: FlowStats::default(),
+            }
+        }
+
+        /// Sets the address of the client if the flow is a socket.
+        pub fn peer_addr(mut self, addr: SocketAddr) -> Self {
+            self.peer_addr = Some(addr);
+            self
+        }
+
+        /// Limits the length of a line (without the newline) in bytes.
+        /// Longer lines are skipped and reported as `LineTooLong`.
+        /// It limits messages of any framing.
+        pub fn max_line_length(mut self, limit: usize) -> Self {
+            self.max_line = Some(limit);
+            self
+        }
+
+        /// Delimits messages in both directions. It's `Lines` by default.
+        pub fn framing(mut self, framing: Framing) -> Self {
+            self.framing = framing;
+            self
+        }
+
+        /// Reads a non-blocking reader on the thread which pulls, so the
+        /// flow can be polled without a thread of its own. A message is
+        /// taken when it's whole, `pull` waits for it.
+        pub fn polled(mut self) -> Self {
+            if let Source::Inline(reader) = self.source {
+                let partial = Partial {
+                    buf: reader.buffer().to_vec(),
+                    ..Partial::default()
+                };
+                self.source = Source::Polled(reader.into_inner(), partial);
+            }
+            self
+        }
+
+        fn read(&mut self) -> Line {
+            let (framing, max_line) = (self.framing, self.max_line);
+            match self.source {
+                Source::Inline(ref mut reader) => match framing {
+                    Framing::Lines => read_line(reader, max_line),
+                    Framing::LengthPrefixed => read_prefixed(reader, max_line),
+                },
+                Source::Polled(ref mut reader, ref mut partial) => loop {
+                    if let Some(line) = partial.poll(reader, framing, max_line) {
+                        break line;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                },
+                Source::Threaded(..) => {
+                    match self.lines().recv() {
+                        Ok(line) => line,
+                        // The reading has ended
+                        Err(_) => (Ok(None), 0),
+                    }
+                }
+            }
+        }
+
+        /// Messages read by the thread, it's started if it isn't yet.
+        fn lines(&mut self) -> &Receiver<Line> {
+            let (framing, max_line) = (self.framing, self.max_line);
+            match self.source {
+                Source::Threaded(ref mut start, ref mut lines) => {
+                    if let Some((reader, spawn)) = start.take() {
+                        *lines = Some(spawn(reader, framing, max_line));
+                    }
+                    lines.as_ref().expect("lines are read")
+                }
+                _ => panic!("lines of the flow aren't read by a thread"),
+            }
+        }
+
+        fn accept(&mut self, line: Line) -> Result<Option<String>, flow::Error> {
+            let (content, read) = line;
+            if read > 0 {
+                self.stats.frames_received += 1;
+                self.stats.bytes_received += read as u64;
+            }
+            content
+        }
+    }
+
+    impl<R: Read + Send + 'static, W: Write> IoFlow<R, W> {
+        /// Reads messages on a separate thread, so the flow can be polled
+        /// and outputs of background tasks are sent while the client is
+        /// silent. The thread ends when the reader ends or, after the flow
+        /// is dropped, when its next read returns. A reader which blocks
+        /// forever (stdin) keeps its thread, a socket should be shut down
+        /// when the flow is dropped (see `tcpmould::TcpFlow`).
+        pub fn threaded(mut self) -> Self {
+            if let Source::Inline(reader) = self.source {
+                self.source = Source::Threaded(Some((reader, spawn_reader::<R>)), None);
+            }
+            self
+        }
+    }
+
+    impl IoFlow<io::Stdin, io::Stdout> {
+        pub fn stdio() -> Self {
+            IoFlow::new("STDIO", io::stdin(), io::stdout())
+        }
+    }
+
+    fn spawn_reader<R: Read + Send + 'static>(reader: BufReader<R>, framing: Framing, max_line: Option<usize>) -> Receiver<Line> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || read_lines(reader, framing, max_line, tx));
+        rx
+    }
+
+    /// Sends messages until the reader ends or the flow is dropped.
+    fn read_lines<R: Read>(mut reader: BufReader<R>, framing: Framing, max_line: Option<usize>, tx: Sender<Line>) {
+        loop {
+            let line = match framing {
+                Framing::Lines => read_line(&mut reader, max_line),
+                Framing::LengthPrefixed => read_prefixed(&mut reader, max_line),
+            };
+            let more = match line.0 {
+                Ok(Some(_)) | Err(flow::Error::LineTooLong) => true,
+                _ => false,
+            };
+            if tx.send(line).is_err() || !more {
+                break;
+            }
+        }
+    }
+
+    fn read_line<R: Read>(reader: &mut BufReader<R>, max_line: Option<usize>) -> Line {
+        let mut buf = Vec::new();
+        // Take two extra bytes to fit the `\r\n` of the longest line
+        let take = max_line.map_or(::std::u64::MAX, |limit| limit as u64 + 2);
+        let read = match reader.take(take).read_until(b'\n', &mut buf) {
+            Ok(read) => read,
+            Err(err) => return (Err(err.into()), 0),
+        };
+        if read == 0 {
+            return (Ok(None), 0);
+        }
+        // The last line may have no newline
+        let ended = buf.last() == Some(&b'\n');
+        if ended {
+            buf.pop();
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+        }
+        // The limit is checked without the terminator
+        if max_line.map_or(false, |limit| buf.len() > limit) {
+            if ended {
+                return (Err(flow::Error::LineTooLong), read);
+            }
+            return match skip_line(reader) {
+                Ok(skipped) => (Err(flow::Error::LineTooLong), read + skipped),
+                Err(err) => (Err(err.into()), read),
+            };
+        }
+        let content = String::from_utf8(buf).map_err(|_| flow::Error::BadMessageEncoding);
+        (content.map(Some), read)
+    }
+
+    fn read_prefixed<R: Read>(reader: &mut BufReader<R>, max_line: Option<usize>) -> Line {
+        let mut prefix = [0u8; 4];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match reader.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return (Ok(None), 0),
+                Ok(0) => return (Err(flow::Error::ConnectionBroken), filled),
+                Ok(read) => filled += read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return (Err(err.into()), filled),
+            }
+        }
+        let length = prefix.iter().fold(0u64, |length, byte| length << 8 | u64::from(*byte));
+        if max_line.map_or(false, |limit| length > limit as u64) {
+            let skipped = io::copy(&mut reader.take(length), &mut io::sink()).unwrap_or(0);
+            let result = if skipped < length {
+                Err(flow::Error::ConnectionBroken)
+            } else {
+                Err(flow::Error::LineTooLong)
+            };
+            return (result, prefix.len() + skipped as usize);
+        }
+        // Data is taken as it comes, a wrong length allocates nothing
+        let mut buf = Vec::new();
+        let read = match reader.take(length).read_to_end(&mut buf) {
+            Ok(read) => read,
+            Err(err) => return (Err(err.into()), prefix.len()),
+        };
+        if (read as u64) < length {
+            return (Err(flow::Error::ConnectionBroken), prefix.len() + read);
+        }
+        let content = String::from_utf8(buf).map_err(|_| flow::Error::BadMessageEncoding);
+        (content.map(Some), prefix.len() + read)
+    }
+
+    fn skip_line<R: Read>(reader: &mut BufReader<R>) -> io::Result<usize> {
+        let mut skipped = 0;
+        loop {
+            let (found, used) = {
+                let available = reader.fill_buf()?;
+                if available.is_empty() {
+                    return Ok(skipped);
+                }
+                match available.iter().position(|b| *b == b'\n') {
+                    Some(pos) => (true, pos + 1),
+                    None => (false, available.len()),
+                }
+            };
+            reader.consume(used);
+            skipped += used;
+            if found {
+                return Ok(skipped);
+            }
+        }
+    }
+
+    impl<R: Read, W: Write> Flow for IoFlow<R, W> {
+        fn who(&self) -> String {
+            self.who.clone()
+        }
+
+        fn info(&self) -> ConnectionInfo {
+            ConnectionInfo::new(self.who.clone(), self.peer_addr)
+        }
+
+        fn pull(&mut self) -> Result<Option<String>, flow::Error> {
+            let line = self.read();
+            self.accept(line)
+        }
+
+        fn try_pull(&mut self) -> Result<Pull, flow::Error> {
+            let (framing, max_line) = (self.framing, self.max_line);
+            let line = match self.source {
+                Source::Inline(_) => {
+                    return Ok(match self.pull()? {
+                        Some(content) => Pull::Message(content),
+                        None => Pull::Closed,
+                    });
+                }
+                Source::Polled(ref mut reader, ref mut partial) => match partial.poll(reader, framing, max_line) {
+                    Some(line) => line,
+                    None => return Ok(Pull::Empty),
+                },
+                Source::Threaded(..) => match self.lines().try_recv() {
+                    Ok(line) => line,
+                    Err(TryRecvError::Empty) => return Ok(Pull::Empty),
+                    Err(TryRecvError::Disconnected) => return Ok(Pull::Closed),
+                },
+            };
+            Ok(match self.accept(line)? {
+                Some(content) => Pull::Message(content),
+                None => Pull::Closed,
+            })
+        }
+
+        fn push(&mut self, content: String) -> Result<(), flow::Error> {
+            let delimiter = match self.framing {
+                Framing::Lines => {
+                    self.writer.write_all(content.as_bytes())?;
+                    self.writer.write_all(&['\n' as u8])?;
+                    1
+                }
+                Framing::LengthPrefixed => {
+                    let length = content.len() as u32;
+                    let prefix = [(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+                    self.writer.write_all(&prefix)?;
+                    self.writer.write_all(content.as_bytes())?;
+                    prefix.len()
+                }
+            };
+            self.writer.flush()?;
+            self.stats.frames_sent += 1;
+            self.stats.bytes_sent += (content.len() + delimiter) as u64;
+            Ok(())
+        }
+
+        fn stats(&self) -> FlowStats {
+            self.stats
+        }
+    }
+
+    pub fn start<T>(suite: Arc<super::Suite<T>>)
+    where
+        T: Session,
+    {
+        // Outputs of background tasks go out while stdin is silent, the
+        // reading thread ends with the process
+        let mut client = IoFlow::stdio().threaded();
+        if let Some(limit) = suite.max_message_bytes {
+            client = client.max_line_length(limit);
+        }
+        // Use Arc to allow joining diferent start functions
+        debug!("Connection from {}", client.who());
+        super::process_session(suite.as_ref(), client);
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use std::io::BufReader;
+        use flow;
+        use super::read_line;
+
+        fn lines(input: &[u8], limit: usize) -> Vec<Result<Option<String>, flow::Error>> {
+            let mut reader = BufReader::new(input);
+            let mut lines = Vec::new();
+            loop {
+                let (line, _) = read_line(&mut reader, Some(limit));
+                let end = match line {
+                    Ok(None) => true,
+                    _ => false,
+                };
+                lines.push(line);
+                if end {
+                    return lines;
+                }
+            }
+        }
+
+        #[test]
+        fn overlong_line_is_skipped() {
+            let lines = lines(b"0123456789abcdef\n{\"id\":1}\n", 8);
+            assert_eq!(lines.len(), 3);
+            match lines[0] {
+                Err(flow::Error::LineTooLong) => (),
+                ref other => panic!("overlong line is read: {:?}", other),
+            }
+            assert_eq!(lines[1].as_ref().unwrap().as_ref().unwrap(), "{\"id\":1}");
+        }
+
+        #[test]
+        fn crlf_of_longest_line_fits() {
+            let lines = lines(b"01234567\r\n012345678\r\nabc", 8);
+            assert_eq!(lines[0].as_ref().unwrap().as_ref().unwrap(), "01234567");
+            match lines[1] {
+                Err(flow::Error::LineTooLong) => (),
+                ref other => panic!("overlong line is read: {:?}", other),
+            }
+            assert_eq!(lines[2].as_ref().unwrap().as_ref().unwrap(), "abc");
+        }
+    }
+}
+
+#[cfg(feature = "tcpmould")]
+pub mod tcpmould {
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::io::{self, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+    use serde_json;
+    use session::{Session, Output, TaskResult, Failure, code};
+    use flow::{self, Flow, FlowStats, Pull, ConnectionInfo};
+    use super::{Listening, SessionGuard, ServerHandle, Driver};
+    use super::iomould::IoFlow;
+
+    /// Newline-delimited JSON over a TCP connection. Messages are read on
+    /// a thread of their own, or polled from a non-blocking socket. The
+    /// socket is shut down when the flow is closed or dropped, so the
+    /// client sees the end and the thread stops.
+    pub struct TcpFlow {
+        flow: IoFlow<TcpStream, Patient>,
+        stream: TcpStream,
+    }
+
+    /// Writes to a socket which may be non-blocking, waits while the write
+    /// would block.
+    struct Patient(TcpStream);
+
+    impl Write for Patient {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            loop {
+                match self.0.write(buf) {
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl TcpFlow {
+        /// Splits a connection, `max_line_length` limits the length of a
+        /// message (see `IoFlow::max_line_length`).
+        pub fn new(stream: TcpStream, max_line_length: Option<usize>) -> io::Result<Self> {
+            Self::split(stream, max_line_length, false)
+        }
+
+        /// Splits a connection without a reader thread, the socket becomes
+        /// non-blocking and `try_pull` polls it. For sessions of a `Driver`.
+        pub fn polled(stream: TcpStream, max_line_length: Option<usize>) -> io::Result<Self> {
+            stream.set_nonblocking(true)?;
+            Self::split(stream, max_line_length, true)
+        }
+
+        fn split(stream: TcpStream, max_line_length: Option<usize>, polled: bool) -> io::Result<Self> {
+            let addr = stream.peer_addr().ok();
+            let who = match addr {
+                Some(addr) => format!("TCP IP {}", addr),
+                None => "TCP".to_owned(),
+            };
+            let reader = stream.try_clone()?;
+            let writer = Patient(stream.try_clone()?);
+            let mut flow = IoFlow::new(&who, reader, writer);
+            if let Some(limit) = max_line_length {
+                flow = flow.max_line_length(limit);
+            }
+            let flow = if polled { flow.polled() } else { flow.threaded() };
+            Ok(TcpFlow {
+                flow: match addr {
+                    Some(addr) => flow.peer_addr(addr),
+                    None => flow,
+                },
+                stream: stream,
+            })
+        }
+    }
+
+    impl Flow for TcpFlow {
+        fn who(&self) -> String {
+            self.flow.who()
+        }
+
+        fn info(&self) -> ConnectionInfo {
+            self.flow.info()
+        }
+
+        fn pull(&mut self) -> Result<Option<String>, flow::Error> {
+            self.flow.pull()
+        }
+
+        fn try_pull(&mut self) -> Result<Pull, flow::Error> {
+            self.flow.try_pull()
+        }
+
+        fn push(&mut self, content: String) -> Result<(), flow::Error> {
+            self.flow.push(content)
+        }
+
+        fn stats(&self) -> FlowStats {
+            self.flow.stats()
+        }
+
+        fn close(&mut self, _code: u16, _reason: &str) -> Result<(), flow::Error> {
+            // The peer could drop the connection first
+            self.stream.shutdown(Shutdown::Both).ok();
+            Ok(())
+        }
+    }
+
+    impl Drop for TcpFlow {
+        fn drop(&mut self) {
+            self.stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+
+    pub fn start<T, A>(addr: A, suite: Arc<super::Suite<T>>)
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        spawn(addr, suite).join();
+    }
+
+    /// Serves clients in background, the handle stops it.
+    pub fn spawn<T, A>(addr: A, suite: Arc<super::Suite<T>>) -> ServerHandle
+    where
+        A: ToSocketAddrs,
+        T: Session,
+    {
+        // Fail if can't bind, safe to unwrap
+        let listener = TcpListener::bind(addr).unwrap();
+        // Lets the loop check shutdown between connections
+        listener.set_nonblocking(true).expect("can't use non-blocking listener");
+        let local = listener.local_addr().expect("listener has an address");
+        ServerHandle::spawn(local, move |listening| serve(listener, suite, listening))
+    }
+
+    fn reject(mut stream: TcpStream) {
+        debug!("Reject a connection, the server is busy");
+        let out = Output::new(0, TaskResult::Fail(Failure::new(code::SERVER_BUSY, "server busy")));
+        if let Ok(content) = serde_json::to_string(&out) {
+            stream.set_nonblocking(false).ok();
+            writeln!(stream, "{}", content).ok();
+        }
+    }
+
+    fn serve<T: Session>(listener: TcpListener, suite: Arc<super::Suite<T>>, listening: Arc<Listening>) {
+        let mut driver = suite.session_threads.map(|threads| Driver::new(suite.clone(), threads));
+        while !listening.shutdown.load(Ordering::SeqCst) {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => {
+                    thread::sleep(Duration::from_millis(50));
+                    continue;
+                }
+            };
+            let guard = match SessionGuard::enter(&listening, &suite) {
+                Some(guard) => guard,
+                None => {
+                    reject(stream);
+                    continue;
+                }
+            };
+            match driver {
+                Some(ref mut driver) => {
+                    if let Some(client) = open(stream, &suite, true) {
+                        driver.run(client, guard);
+                    }
+                }
+                None => {
+                    let suite = suite.clone();
+                    thread::spawn(move || {
+                        let _guard = guard;
+                        if let Some(client) = open(stream, &suite, false) {
+                            super::process_session(suite.as_ref(), client);
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// A driver polls the flow, a session on a thread of its own reads it
+    /// on another one.
+    fn open<T: Session>(stream: TcpStream, suite: &super::Suite<T>, polled: bool) -> Option<TcpFlow> {
+        let limit = suite.max_message_bytes;
+        let flow = if polled {
+            TcpFlow::polled(stream, limit)
+        } else {
+            // Some platforms pass non-blocking mode of a listener on
+            stream.set_nonblocking(false).and_then(|_| TcpFlow::new(stream, limit))
+        };
+        let flow = match flow {
+            Ok(flow) => flow,
+            Err(err) => {
+                warn!("Can't split a connection: {}", err);
+                return None;
+            }
+        };
+        debug!("Connection from {}", flow.who());
+        Some(flow)
     }
 }
@@ -1,7 +1,14 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::Duration;
+use futures::{Async, Future};
+use futures::executor::{self, Notify};
 use serde::{Deserialize, Serialize};
 use serde_json::{self, Value};
-use session::Session;
-use worker::{self, Worker};
+use session::{Session, Emitter, code};
+use worker::{self, Worker, StreamWorker, BackgroundWorker, PagedWorker, AsyncWorker, Stream};
+use permission::{self, Rights, Require};
 
 #[derive(Debug, Fail)]
 pub enum Error {
@@ -19,27 +26,467 @@ pub type Result<T> = ::std::result::Result<T, Error>;
 pub trait Service<T: Session>: Send + Sync + 'static {
     /// Never return error, but rejecting Worker created
     fn route(&self, action: &str) -> Result<Action<T>>;
+
+    /// Routes by the session too (to give admins another worker). It's
+    /// `route` by default, the server always routes with it.
+    fn route_with(&self, action: &str, _session: &T) -> Result<Action<T>> {
+        self.route(action)
+    }
+
+    /// Actions listed to clients by introspection. It's informational,
+    /// routing decides which actions exist. An empty list means the
+    /// service hasn't declared its actions, not that it has none.
+    fn actions(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+}
+
+/// Service which handles all its actions with one instance, so its state
+/// (an index, a cache) is shared by every session as long as the suite
+/// lives. Register it wrapped with `Stateful`.
+pub trait StatefulService<T: Session>: Send + Sync + 'static {
+    /// Tells whether the action exists, it's checked on routing.
+    fn has_action(&self, action: &str) -> bool;
+
+    fn perform(&self, session: &mut T, action: &str, payload: Value) -> worker::Result<Value>;
+
+    fn actions(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+}
+
+pub struct Stateful<S>(Arc<S>);
+
+impl<S> Stateful<S> {
+    pub fn new(service: S) -> Self {
+        Stateful(Arc::new(service))
+    }
+}
+
+impl<T: Session, S: StatefulService<T>> Service<T> for Stateful<S> {
+    fn route(&self, action: &str) -> Result<Action<T>> {
+        if !self.0.has_action(action) {
+            return Err(Error::ActionNotFound);
+        }
+        let service = self.0.clone();
+        let action = action.to_owned();
+        let perform = move |session: &mut T, value: Value, emitter: &mut Emitter| {
+            let result = service.perform(session, &action, value)?;
+            emitter.item(result)?;
+            emitter.done()?;
+            Ok(None)
+        };
+        Ok(Action::new(perform, false))
+    }
+
+    fn actions(&self) -> Vec<&'static str> {
+        self.0.actions()
+    }
 }
 
+type Handler<T> = Fn(&mut T, Value) -> worker::Result<Value> + Send + Sync;
+
+/// Service of closures, an action is performed by the closure
+/// registered under its name as `Worker::perform` would do it:
+/// `FnService::new().action("do-it", |session, name: String| Ok(name))`.
+pub struct FnService<T> {
+    handlers: HashMap<&'static str, Arc<Handler<T>>>,
+}
+
+impl<T: Session> FnService<T> {
+    pub fn new() -> Self {
+        FnService {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Adds an action, the payload is read as `I` and the result is
+    /// sent as an item.
+    pub fn action<F, I, O>(mut self, action: &'static str, handler: F) -> Self
+    where
+        for<'de> I: Deserialize<'de>,
+        O: Serialize,
+        F: Fn(&mut T, I) -> worker::Result<O> + Send + Sync + 'static,
+    {
+        let handler = move |session: &mut T, value: Value| {
+            let input = payload(value)?;
+            let output = handler(session, input)?;
+            serde_json::to_value(output).map_err(worker::Error::from)
+        };
+        self.handlers.insert(action, Arc::new(handler));
+        self
+    }
+}
+
+impl<T: Session> Default for FnService<T> {
+    fn default() -> Self {
+        FnService::new()
+    }
+}
+
+impl<T: Session> Service<T> for FnService<T> {
+    fn route(&self, action: &str) -> Result<Action<T>> {
+        let handler = self.handlers.get(action).ok_or(Error::ActionNotFound)?.clone();
+        let perform = move |session: &mut T, value: Value, emitter: &mut Emitter| {
+            let result = handler(session, value)?;
+            emitter.item(result)?;
+            emitter.done()?;
+            Ok(None)
+        };
+        Ok(Action::new(perform, false))
+    }
+
+    fn actions(&self) -> Vec<&'static str> {
+        let mut actions: Vec<_> = self.handlers.keys().cloned().collect();
+        actions.sort();
+        actions
+    }
+}
+
+/// Remainder of an action which the session runs on a separate thread.
+pub trait Task: Send {
+    fn run(self: Box<Self>, emitter: &mut Emitter) -> worker::Result<()>;
+}
+
+impl<F> Task for F
+where
+    F: FnOnce(&mut Emitter) -> worker::Result<()> + Send,
+{
+    fn run(self: Box<Self>, emitter: &mut Emitter) -> worker::Result<()> {
+        (*self)(emitter)
+    }
+}
+
+/// Drops an item which equals the item sent just before it, everything
+/// else passes through.
+struct Distinct<'a> {
+    emitter: &'a mut Emitter,
+    last: Option<Value>,
+}
+
+impl<'a> Distinct<'a> {
+    fn new(emitter: &'a mut Emitter) -> Self {
+        Distinct {
+            emitter: emitter,
+            last: None,
+        }
+    }
+}
+
+impl<'a> Emitter for Distinct<'a> {
+    fn item(&mut self, value: Value) -> worker::Result<()> {
+        if self.last.as_ref() == Some(&value) {
+            return Ok(());
+        }
+        self.emitter.item(value.clone())?;
+        self.last = Some(value);
+        Ok(())
+    }
+
+    fn progress(&mut self, fraction: f64, note: Option<String>) -> worker::Result<()> {
+        self.emitter.progress(fraction, note)
+    }
+
+    fn done(&mut self) -> worker::Result<()> {
+        self.emitter.done()
+    }
+
+    fn binary(&mut self, meta: Value, data: Vec<u8>) -> worker::Result<()> {
+        self.emitter.binary(meta, data)
+    }
+
+    fn more(&mut self) -> worker::Result<()> {
+        self.emitter.more()
+    }
+
+    fn is_canceled(&self) -> bool {
+        self.emitter.is_canceled()
+    }
+}
+
+/// Counts bytes of items and binary data, outputs over the limit fail.
+struct Budget<'a> {
+    emitter: &'a mut Emitter,
+    limit: usize,
+    spent: usize,
+}
+
+impl<'a> Budget<'a> {
+    fn new(emitter: &'a mut Emitter, limit: usize) -> Self {
+        Budget {
+            emitter: emitter,
+            limit: limit,
+            spent: 0,
+        }
+    }
+
+    fn spend(&mut self, bytes: usize) -> worker::Result<()> {
+        self.spent = self.spent.saturating_add(bytes);
+        self.check()
+    }
+
+    fn check(&self) -> worker::Result<()> {
+        if self.spent > self.limit {
+            Err(worker::Error::reject(code::TOO_LARGE, "response too large"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a> Emitter for Budget<'a> {
+    fn item(&mut self, value: Value) -> worker::Result<()> {
+        self.spend(value.to_string().len())?;
+        self.emitter.item(value)
+    }
+
+    fn progress(&mut self, fraction: f64, note: Option<String>) -> worker::Result<()> {
+        self.check()?;
+        self.emitter.progress(fraction, note)
+    }
+
+    fn done(&mut self) -> worker::Result<()> {
+        self.check()?;
+        self.emitter.done()
+    }
+
+    fn binary(&mut self, meta: Value, data: Vec<u8>) -> worker::Result<()> {
+        self.spend(meta.to_string().len() + data.len())?;
+        self.emitter.binary(meta, data)
+    }
+
+    fn more(&mut self) -> worker::Result<()> {
+        self.check()?;
+        self.emitter.more()
+    }
+
+    fn is_canceled(&self) -> bool {
+        self.emitter.is_canceled()
+    }
+}
+
+/// Reads a payload as an input of a worker. A payload of a wrong shape
+/// rejects the request with `BAD_REQUEST`, other requests go on.
+fn payload<I>(value: Value) -> worker::Result<I>
+where
+    for<'de> I: Deserialize<'de>,
+{
+    serde_json::from_value(value).map_err(|err| {
+        worker::Error::reject(code::BAD_REQUEST, format!("bad payload: {}", err))
+    })
+}
+
+/// Performs a request on the session thread. Returns a task if the rest
+/// of the work has to be done in background.
 pub struct Action<T: 'static> {
-    pub perform: Box<FnMut(&mut T, Value) -> worker::Result<Value>>,
+    pub perform: Box<FnMut(&mut T, Value, &mut Emitter) -> worker::Result<Option<Box<Task>>>>,
+    /// Rights checked before the action performs
+    pub requires: Option<Box<Fn(&T) -> permission::Result<()>>>,
+    /// Whether the action returns a task to run in background, only such
+    /// tasks can be timed out
+    pub background: bool,
+    /// Limit of the task which overrides the task timeout of the suite
+    pub timeout: Option<Duration>,
+    /// Budget of output bytes set by `max_output_bytes`, the suite's
+    /// budget applies to actions without one
+    pub max_output_bytes: Option<usize>,
 }
 
 impl<T: Session> Action<T> {
+    /// Action without rights, limits and budgets.
+    fn new<F>(perform: F, background: bool) -> Self
+    where
+        F: FnMut(&mut T, Value, &mut Emitter) -> worker::Result<Option<Box<Task>>> + 'static,
+    {
+        Action {
+            perform: Box::new(perform),
+            requires: None,
+            background: background,
+            timeout: None,
+            max_output_bytes: None,
+        }
+    }
+
+    /// Declares a right the session must have, the request is denied
+    /// without performing otherwise. Every declared right is required.
+    pub fn requires<R: Rights + 'static>(mut self, right: R) -> Self
+    where
+        T: Require<R>,
+    {
+        let previous = self.requires.take();
+        let check = move |session: &T| {
+            if let Some(ref previous) = previous {
+                previous(session)?;
+            }
+            session.require(&right)
+        };
+        self.requires = Some(Box::new(check));
+        self
+    }
+
+    /// Doesn't send an item equal to the item sent just before it, e.g.
+    /// `a, a, b, b, a` goes out as `a, b, a`. Only adjacent items are
+    /// compared, `done` and failures always go out.
+    pub fn distinct(mut self) -> Self {
+        let mut perform = self.perform;
+        let distinct = move |session: &mut T, value: Value, emitter: &mut Emitter| {
+            let task = perform(session, value, &mut Distinct::new(emitter))?;
+            Ok(task.map(|task| {
+                let task = move |emitter: &mut Emitter| task.run(&mut Distinct::new(emitter));
+                Box::new(task) as Box<Task>
+            }))
+        };
+        self.perform = Box::new(distinct);
+        self
+    }
+
+    /// Fails the request with `TOO_LARGE` once its items and binary data
+    /// take more than `limit` bytes. The item over the budget isn't sent
+    /// and every later output of the worker fails, so it stops.
+    pub fn max_output_bytes(mut self, limit: usize) -> Self {
+        let mut perform = self.perform;
+        let budgeted = move |session: &mut T, value: Value, emitter: &mut Emitter| {
+            let task = perform(session, value, &mut Budget::new(emitter, limit))?;
+            Ok(task.map(|task| {
+                let task = move |emitter: &mut Emitter| task.run(&mut Budget::new(emitter, limit));
+                Box::new(task) as Box<Task>
+            }))
+        };
+        self.perform = Box::new(budgeted);
+        self.max_output_bytes = Some(limit);
+        self
+    }
+
+    /// Fails the task with `TIMEOUT` if it runs longer than `timeout`,
+    /// whatever the task timeout of the suite is. Only actions in
+    /// background can be timed out, others run untimed.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub fn from_worker<W, I, O>(mut worker: W) -> Self
     where
         for<'de> I: Deserialize<'de>,
         O: Serialize,
         W: Worker<T, In = I, Out = O> + 'static,
     {
-        let perform = move |session: &mut T, value: Value| {
-            let input = serde_json::from_value(value)?;
+        let perform = move |session: &mut T, value: Value, emitter: &mut Emitter| {
+            let input = payload(value)?;
             let output = worker.perform(session, input)?;
             let result = serde_json::to_value(output)?;
-            Ok(result)
+            emitter.item(result)?;
+            emitter.done()?;
+            Ok(None)
         };
-        Action {
-            perform: Box::new(perform),
+        Action::new(perform, false)
+    }
+
+    pub fn from_stream_worker<W, I, O>(mut worker: W) -> Self
+    where
+        for<'de> I: Deserialize<'de>,
+        O: Serialize,
+        W: StreamWorker<T, In = I, Out = O> + 'static,
+    {
+        let perform = move |session: &mut T, value: Value, emitter: &mut Emitter| {
+            let input = payload(value)?;
+            worker.perform(session, input, &mut Stream::new(emitter))?;
+            emitter.done()?;
+            Ok(None)
+        };
+        Action::new(perform, false)
+    }
+
+    pub fn from_background_worker<W, I, O>(mut worker: W) -> Self
+    where
+        for<'de> I: Deserialize<'de>,
+        O: Serialize + 'static,
+        W: BackgroundWorker<T, In = I, Out = O> + 'static,
+    {
+        let perform = move |session: &mut T, value: Value, _: &mut Emitter| {
+            let input = payload(value)?;
+            let job = worker.prepare(session, input)?;
+            let task = move |emitter: &mut Emitter| {
+                job.run(&mut Stream::new(emitter))?;
+                emitter.done()
+            };
+            Ok(Some(Box::new(task) as Box<Task>))
+        };
+        Action::new(perform, true)
+    }
+
+    pub fn from_paged_worker<W, I, O>(mut worker: W) -> Self
+    where
+        for<'de> I: Deserialize<'de>,
+        O: Serialize + 'static,
+        W: PagedWorker<T, In = I, Out = O> + 'static,
+    {
+        let perform = move |session: &mut T, value: Value, _: &mut Emitter| {
+            let input = payload(value)?;
+            let page_size = worker.page_size().max(1);
+            let items = worker.prepare(session, input)?;
+            let task = move |emitter: &mut Emitter| {
+                let mut items = items;
+                loop {
+                    for _ in 0..page_size {
+                        match items.next() {
+                            Some(item) => Stream::new(emitter).emit(item)?,
+                            None => return emitter.done(),
+                        }
+                    }
+                    emitter.more()?;
+                }
+            };
+            Ok(Some(Box::new(task) as Box<Task>))
+        };
+        Action::new(perform, true)
+    }
+
+    pub fn from_async_worker<W, I, O>(mut worker: W) -> Self
+    where
+        for<'de> I: Deserialize<'de>,
+        O: Serialize + 'static,
+        W: AsyncWorker<T, In = I, Out = O> + 'static,
+    {
+        let perform = move |session: &mut T, value: Value, _: &mut Emitter| {
+            let input = payload(value)?;
+            let future = worker.perform(session, input);
+            let task = move |emitter: &mut Emitter| {
+                let output = resolve(future, emitter)?;
+                let result = serde_json::to_value(output)?;
+                emitter.item(result)?;
+                emitter.done()
+            };
+            Ok(Some(Box::new(task) as Box<Task>))
+        };
+        Action::new(perform, true)
+    }
+}
+
+struct Unpark(Thread);
+
+impl Notify for Unpark {
+    fn notify(&self, _: usize) {
+        self.0.unpark();
+    }
+}
+
+/// Polls a future on the current thread until it's ready or the client
+/// cancels the task.
+fn resolve<F>(future: F, emitter: &Emitter) -> worker::Result<F::Item>
+where
+    F: Future<Error = worker::Error>,
+{
+    let notify = Arc::new(Unpark(thread::current()));
+    let mut spawn = executor::spawn(future);
+    loop {
+        if let Async::Ready(item) = spawn.poll_future_notify(&notify, 0)? {
+            return Ok(item);
+        }
+        if emitter.is_canceled() {
+            return Err(worker::Error::Interrupted);
         }
+        // Wakes up on notification, checks cancellation in a while anyway
+        thread::park_timeout(Duration::from_millis(50));
     }
 }
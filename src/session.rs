@@ -2,62 +2,573 @@
 //!
 //! Server can receive the following messages from clients:
 //!
-//! * {"event": "request", "data": {"action": "what_to_do", "payload": {...}}}
-//! * {"event": "next"}
-//! * {"event": "cancel"}
+//! * {"id": 1, "service": "service_name", "action": "what_to_do", "payload": {...}}
+//! * {"event": "cancel", "id": 1}
+//! * {"event": "next", "id": 1}
 //!
-//! Server responds to clients the following messages:
+//! An id is chosen by the client, it's `0` if omitted. Requests without
+//! an id share it, so they're one-shot: while one of them runs in
+//! background another one is rejected with `CONFLICT`. A client which
+//! runs requests concurrently gives each its own id. Server responds
+//! to every request with any number of items followed by exactly one
+//! terminal message, `done` or `fail`:
 //!
-//! * {"event": "ready"}
-//! * {"event": "item"}
-//! * {"event": "done"}
-//! * {"event": "reject", "data": {"message": "text_of_message"}}
+//! * {"id": 1, "result": {"event": "item", "data": {...}}}
+//! * {"id": 1, "result": {"event": "progress", "data": {"fraction": 0.5, "note": "..."}}}
+//! * {"id": 1, "result": {"event": "done"}}
+//! * {"id": 1, "result": {"event": "fail", "data": {"code": "SERVICE_NOT_FOUND", "message": "service not found"}}}
+//!
+//! Outputs echo `service` and `action` of the request, e.g.
+//! `{"id": 1, "service": "hello", "action": "do-it", "result": {...}}`,
+//! they're omitted above for brevity and are absent when a message
+//! couldn't be read as a request.
+//!
+//! A failure has a stable `code` (see `code` module) to branch on,
+//! a `message` for humans and optional `details`.
+//!
+//! Tasks of background workers run concurrently, outputs of different
+//! ids can interleave. A canceled task gets no more items, its terminal
+//! message is `cancelled` when the task has stopped (or was abandoned
+//! after a grace period):
+//!
+//! * {"id": 1, "result": {"event": "cancelled"}}
+//!
+//! An item can carry bytes, its metadata comes as a `binary` result and
+//! the bytes follow the frame in a binary frame of their own: the id of
+//! the request (8 bytes, big-endian) and the bytes. Flows without binary
+//! frames drop the bytes.
+//!
+//! * {"id": 1, "result": {"event": "binary", "data": {...}}}
+//!
+//! A paged task sends `more` after a page and produces nothing until the
+//! client asks for the next page with `next`:
+//!
+//! * {"id": 1, "result": {"event": "more"}}
+//!
+//! Also server can send:
+//!
+//! * {"id": 1, "result": {"event": "redirect", "data": {"url": "wss://other.node/"}}}
+//! * {"id": 0, "result": {"event": "notify", "data": {...}}}
+//!
+//! Notifications answer no request, the server pushes them whenever
+//! the session has something to tell (see `SessionHandle`).
+//!
+//! After a redirect the server closes the connection with `REDIRECT_CODE`.
+//! A connection rejected by the session builder is closed with
+//! `REJECT_CODE` before any request is read, an idle one is closed with
+//! `IDLE_CODE`.
+//!
+//! A frame can carry a batch, an array of requests. They're performed as
+//! if they came one by one, a malformed one is rejected alone.
+//!
+//! Messages are JSON in text frames unless the suite has another codec,
+//! a binary codec keeps the same fields.
 
+use std::any::{Any, TypeId};
+use std::fmt;
 use std::str;
+use std::thread;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
 use std::default::Default;
 use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, SyncSender, Receiver, RecvTimeoutError, TrySendError};
+use serde::de::IgnoredAny;
 use serde_json;
 pub use serde_json::Value;
-use flow::{self, Flow};
+use flow::{self, Flow, FlowStats, Pull, ConnectionInfo};
+use service::{Action, Task};
+use pool::Pool;
+use codec::{Codec, JsonCodec};
+use worker;
+
+#[derive(Debug, Fail)]
+pub enum BuildError {
+    #[fail(display = "connection rejected: {}", _0)]
+    Rejected(Cow<'static, str>),
+}
+
+impl From<&'static str> for BuildError {
+    fn from(reason: &'static str) -> Self {
+        BuildError::Rejected(Cow::Borrowed(reason))
+    }
+}
+
+impl From<String> for BuildError {
+    fn from(reason: String) -> Self {
+        BuildError::Rejected(Cow::Owned(reason))
+    }
+}
 
 /// Builds user's session and attaches resources like:
 /// database connections, channels, counters.
+///
+/// An error rejects the connection before any request is read, the flow
+/// is closed with `REJECT_CODE`. Workers see only the session, a builder
+/// keeps `info.session_id()` and the peer in it if workers need them.
 pub trait Builder<T: Session>: Send + Sync + 'static {
-    fn build(&self) -> T;
+    fn build(&self, info: &ConnectionInfo) -> ::std::result::Result<T, BuildError>;
+
+    /// Makes a session from a snapshot of a `Persistable` one, which
+    /// could be taken by another process (see `resume::SnapshotStore`).
+    /// Builders can't restore sessions by default.
+    fn restore(&self, _snapshot: Value) -> ::std::result::Result<T, BuildError> {
+        Err(BuildError::from("sessions can't be restored"))
+    }
 }
 
 pub struct DefaultBuilder;
 
 impl<T: Session + Default> Builder<T> for DefaultBuilder {
-    fn build(&self) -> T {
-        T::default()
+    fn build(&self, _: &ConnectionInfo) -> ::std::result::Result<T, BuildError> {
+        Ok(T::default())
     }
 }
 
-pub trait Session: 'static {}
+/// Builds sessions which share state: a connection pool, a cache.
+/// The closure gets the shared state to clone into a new session.
+pub struct ArcBuilder<S, F> {
+    shared: Arc<S>,
+    build: F,
+}
+
+impl<S, F> ArcBuilder<S, F> {
+    pub fn new(shared: S, build: F) -> Self {
+        ArcBuilder {
+            shared: Arc::new(shared),
+            build: build,
+        }
+    }
+
+    pub fn shared(&self) -> &Arc<S> {
+        &self.shared
+    }
+}
+
+impl<T, S, F> Builder<T> for ArcBuilder<S, F>
+where
+    T: Session,
+    S: Send + Sync + 'static,
+    F: Fn(&Arc<S>) -> T + Send + Sync + 'static,
+{
+    fn build(&self, _: &ConnectionInfo) -> ::std::result::Result<T, BuildError> {
+        Ok((self.build)(&self.shared))
+    }
+}
+
+pub trait Session: 'static {
+    /// Gets a handle to push notifications to the client, a session
+    /// which notifies keeps it. A resumed session gets a new one.
+    fn attach(&mut self, _handle: SessionHandle) {}
+}
+
+/// Outputs waiting in the channel of a session and time senders have
+/// waited for room in it.
+#[derive(Default)]
+struct Gauge {
+    pending: AtomicUsize,
+    waited_micros: AtomicUsize,
+}
+
+impl Gauge {
+    fn add_wait(&self, waited: Duration) {
+        let micros = waited.as_secs() as usize * 1_000_000 + waited.subsec_nanos() as usize / 1_000;
+        self.waited_micros.fetch_add(micros, Ordering::SeqCst);
+    }
+
+    fn take_wait(&self) -> Duration {
+        let micros = self.waited_micros.swap(0, Ordering::SeqCst);
+        Duration::new((micros / 1_000_000) as u64, (micros % 1_000_000) as u32 * 1_000)
+    }
+}
+
+/// Sends outputs to the channel of a session and keeps its gauge.
+#[derive(Clone)]
+struct OutputSender {
+    sender: SyncSender<(usize, Output)>,
+    gauge: Arc<Gauge>,
+}
+
+impl OutputSender {
+    /// Waits for room if the channel is full, fails if the session has
+    /// ended.
+    fn send(&self, output: (usize, Output)) -> ::std::result::Result<(), ()> {
+        self.gauge.pending.fetch_add(1, Ordering::SeqCst);
+        let sent = match self.sender.try_send(output) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(output)) => {
+                let started = Instant::now();
+                let sent = self.sender.send(output).map_err(|_| ());
+                self.gauge.add_wait(started.elapsed());
+                sent
+            }
+            Err(TrySendError::Disconnected(_)) => Err(()),
+        };
+        if sent.is_err() {
+            self.gauge.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        sent
+    }
+
+    fn try_send(&self, output: (usize, Output)) -> ::std::result::Result<(), TrySendError<(usize, Output)>> {
+        self.gauge.pending.fetch_add(1, Ordering::SeqCst);
+        let sent = self.sender.try_send(output);
+        if sent.is_err() {
+            self.gauge.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        sent
+    }
+}
+
+/// Pushes notifications to the client of a session from any thread
+/// while the session lives.
+#[derive(Clone)]
+pub struct SessionHandle {
+    session_id: usize,
+    sender: OutputSender,
+    _alive: Arc<()>,
+}
+
+impl SessionHandle {
+    pub fn session_id(&self) -> usize {
+        self.session_id
+    }
+
+    /// Sends `data` as a `notify` result with id `0`. Fails if the
+    /// session has ended or too many outputs wait for the client.
+    pub fn notify(&self, data: Value) -> Result<()> {
+        let out = Output::new(0, TaskResult::Notify(data));
+        self.sender.try_send((NOTIFY_SERIAL, out)).map_err(|err| match err {
+            TrySendError::Full(_) => Error::Overloaded,
+            TrySendError::Disconnected(_) => Error::ConnectionClosed,
+        })
+    }
+}
+
+/// Session which can span a transaction over several requests. Hooks are
+/// called by reserved `__tx__` actions: `begin`, `commit` and `rollback`.
+/// A transaction left open when the session ends is rolled back.
+pub trait Transactional {
+    fn begin(&mut self) -> worker::Result<()>;
+    fn commit(&mut self) -> worker::Result<()>;
+    fn rollback(&mut self) -> worker::Result<()>;
+}
+
+/// Session which logical state can be taken as a value and restored by
+/// `Builder::restore`, to move it to another process. Only the snapshot
+/// moves: the connection, tasks in background and values which aren't
+/// in the snapshot (handles, `Extensions`) stay behind and are lost.
+pub trait Persistable: Session {
+    fn snapshot(&self) -> Value;
+}
+
+/// Values of any types kept by a session between requests, one per type:
+/// a cursor one action opens and another one reads. A session which
+/// lets workers stash values has it as a field.
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<Any + Send>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Extensions::default()
+    }
+
+    pub fn get<V: Any + Send>(&self) -> Option<&V> {
+        self.values.get(&TypeId::of::<V>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<V: Any + Send>(&mut self) -> Option<&mut V> {
+        self.values.get_mut(&TypeId::of::<V>()).and_then(|value| value.downcast_mut())
+    }
+
+    /// Keeps a value, returns the previous value of its type.
+    pub fn set<V: Any + Send>(&mut self, value: V) -> Option<V> {
+        self.values
+            .insert(TypeId::of::<V>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn remove<V: Any + Send>(&mut self) -> Option<V> {
+        self.values
+            .remove(&TypeId::of::<V>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+}
 
 /// Binds client connection instance to session
 pub struct Context<T: Session, R: Flow> {
     client: R,
-    session: T,
+    /// Taken only when the context ends
+    session: Option<T>,
+    who: String,
+    session_id: usize,
+    ndjson: bool,
+    codec: Arc<Codec>,
+    /// Unterminated request of the last NDJSON message, the next one
+    /// continues it
+    partial: Vec<u8>,
+    /// Codecs to choose from by the first message of the client
+    detected: Option<Vec<Arc<Codec>>>,
+    pending: VecDeque<Vec<u8>>,
+    tasks: HashMap<TaskId, Running>,
+    serial: usize,
+    outputs: OutputSender,
+    collected: Receiver<(usize, Output)>,
+    /// Shared with handles, tells if any is kept
+    handles: Arc<()>,
+    batch: Batch,
+    pool: Option<Arc<Pool>>,
+    blocking: Duration,
+    /// Time tasks have waited for room in the channel of outputs, taken
+    /// from the gauge
+    output_wait: Duration,
+    /// Blocking time since the start of the budget period
+    period_blocking: Duration,
+    period_started: Instant,
+    cancel_grace: Duration,
+    task_timeout: Option<Duration>,
+    page_wait: Duration,
+}
+
+/// Size of a frame with outputs of background tasks. It grows while
+/// outputs pile up and shrinks when the client keeps up.
+struct Batch {
+    min: usize,
+    max: usize,
+    size: usize,
+}
+
+impl Batch {
+    fn adapt(&mut self, taken: usize) {
+        if taken == self.size {
+            self.size = (self.size * 2).min(self.max);
+        } else if taken < self.size / 2 {
+            self.size = (self.size / 2).max(self.min);
+        }
+    }
 }
 
+/// Background task of a session. A serial tells outputs of a task
+/// from outputs of a canceled one with the same id.
+struct Running {
+    serial: usize,
+    started: Instant,
+    canceled: Arc<AtomicBool>,
+    next: Sender<()>,
+    /// When the client has canceled the task
+    canceling: Option<Instant>,
+    /// Limit of the request, it's checked with the task timeout
+    timeout: Option<Duration>,
+}
+
+/// Close code of a connection redirected to another node.
+pub const REDIRECT_CODE: u16 = 4301;
+
+/// Close code of a connection rejected by the session builder.
+pub const REJECT_CODE: u16 = 4403;
+
+/// Close code of a session which got no requests for too long.
+pub const IDLE_CODE: u16 = 4408;
+
+/// Outputs of background tasks a session holds before tasks block.
+const OUTPUT_CAPACITY: usize = 1024;
+
+/// Bytes of an unterminated NDJSON request a session keeps for the next
+/// message, a longer one is rejected.
+const MAX_PARTIAL: usize = 1 << 20;
+
+/// Serial of notifications, tasks get serials from `1`.
+const NOTIFY_SERIAL: usize = 0;
+
 pub type Request = Value;
 
 pub type TaskId = usize;
 
 #[derive(Serialize, Deserialize)]
 pub struct Input {
+    /// Id chosen by the client, `0` if omitted. Only one request of an id
+    /// runs in background at a time.
+    #[serde(default)]
+    pub id: TaskId,
     pub service: String,
     pub action: String,
     pub payload: Value,
+    /// Milliseconds after which the client gives up, a task running
+    /// longer fails with `TIMEOUT`. Actions performed on the session
+    /// thread can't be interrupted, they run untimed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Event of a client about its task.
+#[derive(Deserialize)]
+struct TaskEvent {
+    id: TaskId,
+}
+
+/// Message received from a client.
+pub enum Inbound {
+    Request(Input),
+    Cancel(TaskId),
+    /// Asks a paged task for the next page
+    Next(TaskId),
+}
+
+impl Inbound {
+    /// Tells a cancel event from a request, codecs decode messages
+    /// with it.
+    /// A message with a wrong shape fails as `Malformed` with its id.
+    pub fn from_value(value: Value) -> Result<Self> {
+        let id = value.get("id").and_then(Value::as_u64).unwrap_or(0) as TaskId;
+        let inbound = match value.get("event").and_then(Value::as_str) {
+            Some("cancel") => serde_json::from_value(value).map(|TaskEvent { id }| Inbound::Cancel(id)),
+            Some("next") => serde_json::from_value(value).map(|TaskEvent { id }| Inbound::Next(id)),
+            _ => serde_json::from_value(value).map(Inbound::Request),
+        };
+        inbound.map_err(|err| {
+            Error::Malformed(id, Failure::new(code::BAD_REQUEST, err.to_string()))
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Output {
+    pub id: TaskId,
+    /// Service and action of the request, an output which answers no
+    /// request (a malformed message) has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    pub result: TaskResult,
+    /// Bytes sent in a binary frame after the output
+    #[serde(skip)]
+    pub attachment: Option<Vec<u8>>,
+}
+
+impl Output {
+    pub fn new(id: TaskId, result: TaskResult) -> Self {
+        Output {
+            id: id,
+            service: None,
+            action: None,
+            result: result,
+            attachment: None,
+        }
+    }
+
+    /// Metadata of bytes which are sent in a binary frame.
+    pub fn binary(id: TaskId, meta: Value, data: Vec<u8>) -> Self {
+        let mut out = Output::new(id, TaskResult::Binary(meta));
+        out.attachment = Some(data);
+        out
+    }
+
+    /// Echoes the request the output answers.
+    pub fn echo(mut self, service: &str, action: &str) -> Self {
+        self.service = Some(service.to_owned());
+        self.action = Some(action.to_owned());
+        self
+    }
+}
+
+/// Codes of failures sent to clients.
+pub mod code {
+    pub const SERVICE_NOT_FOUND: &'static str = "SERVICE_NOT_FOUND";
+    pub const ACTION_NOT_FOUND: &'static str = "ACTION_NOT_FOUND";
+    pub const ACCESS_DENIED: &'static str = "ACCESS_DENIED";
+    pub const BAD_REQUEST: &'static str = "BAD_REQUEST";
+    pub const TOO_LARGE: &'static str = "TOO_LARGE";
+    pub const CONFLICT: &'static str = "CONFLICT";
+    pub const OVER_BUDGET: &'static str = "OVER_BUDGET";
+    pub const RATE_LIMITED: &'static str = "RATE_LIMITED";
+    pub const TIMEOUT: &'static str = "TIMEOUT";
+    pub const SERVER_BUSY: &'static str = "SERVER_BUSY";
+    pub const UNIMPLEMENTED: &'static str = "UNIMPLEMENTED";
+    /// Default code of errors raised by workers
+    pub const WORKER_ERROR: &'static str = "WORKER_ERROR";
+    pub const INTERNAL_ERROR: &'static str = "INTERNAL_ERROR";
+}
+
+/// Why a request failed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Failure {
+    pub code: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+impl Failure {
+    pub fn new<M: Into<String>>(code: &str, message: M) -> Self {
+        Failure {
+            code: code.to_owned(),
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "event", content = "data", rename_all = "lowercase")]
-pub enum Output {
+pub enum TaskResult {
     Item(Value),
-    Fail(String),
+    /// Metadata of an item which bytes follow in a binary frame
+    Binary(Value),
+    /// How much of a task is done, it isn't data
+    Progress {
+        fraction: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+    },
+    /// A page is over, the client asks for more with `next`
+    More,
+    Done,
+    Fail(Failure),
+    Cancelled,
+    Redirect { url: String },
+    /// Token to resume the session with, see `resume` module
+    Resume { token: String },
+    /// Pushed by the server, it answers no request
+    Notify(Value),
+}
+
+impl TaskResult {
+    /// Whether nothing follows this result for its task.
+    pub fn is_terminal(&self) -> bool {
+        match *self {
+            TaskResult::Item(_) |
+            TaskResult::Binary(_) |
+            TaskResult::Progress { .. } |
+            TaskResult::More |
+            TaskResult::Resume { .. } |
+            TaskResult::Notify(_) => false,
+            _ => true,
+        }
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -72,6 +583,32 @@ pub enum Error {
     FlowBroken(#[cause] flow::Error),
     #[fail(display = "serde error")]
     SerdeFailed(#[cause] serde_json::Error),
+    #[fail(display = "too many outputs wait for the client")]
+    Overloaded,
+    #[fail(display = "codec error: {}", _0)]
+    CodecFailed(String),
+    /// A message which can't be processed, with the id it seems to have
+    #[fail(display = "malformed message {}: {}", _0, _1)]
+    Malformed(TaskId, Failure),
+}
+
+impl Error {
+    /// Failure which the client gets for the error.
+    pub fn failure(&self) -> Failure {
+        match *self {
+            Error::SerdeFailed(ref cause) => Failure::new(code::BAD_REQUEST, cause.to_string()),
+            Error::CodecFailed(ref reason) => Failure::new(code::BAD_REQUEST, reason.as_str()),
+            Error::FlowBroken(ref cause @ flow::Error::LineTooLong) |
+            Error::FlowBroken(ref cause @ flow::Error::MessageTooLarge) => {
+                Failure::new(code::TOO_LARGE, cause.to_string())
+            }
+            Error::FlowBroken(flow::Error::BadMessageEncoding) => {
+                Failure::new(code::BAD_REQUEST, flow::Error::BadMessageEncoding.to_string())
+            }
+            Error::Malformed(_, ref failure) => failure.clone(),
+            _ => Failure::new(code::INTERNAL_ERROR, self.to_string()),
+        }
+    }
 }
 
 impl From<flow::Error> for Error {
@@ -88,40 +625,731 @@ impl From<serde_json::Error> for Error {
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
+fn push_output<R: Flow + ?Sized>(client: &mut R, codec: &Codec, out: &Output) -> Result<()> {
+    let content = codec.encode(out)?;
+    push_frame(client, codec, content)?;
+    push_attachment(client, out)
+}
+
+fn push_attachment<R: Flow + ?Sized>(client: &mut R, out: &Output) -> Result<()> {
+    let data = match out.attachment {
+        Some(ref data) => data,
+        None => return Ok(()),
+    };
+    let id = out.id as u64;
+    let mut content = Vec::with_capacity(8 + data.len());
+    for shift in (0..8).rev() {
+        content.push((id >> (shift * 8)) as u8);
+    }
+    content.extend_from_slice(data);
+    debug!("Send <= {} bytes of {}", data.len(), out.id);
+    match client.push_binary(content) {
+        Err(flow::Error::BinaryUnsupported) => {
+            warn!("Bytes of {} dropped, the flow has no binary frames", out.id);
+            Ok(())
+        }
+        result => result.map_err(Error::from),
+    }
+}
+
+fn push_frame<R: Flow + ?Sized>(client: &mut R, codec: &Codec, content: Vec<u8>) -> Result<()> {
+    if codec.is_binary() {
+        debug!("Send <= {} bytes", content.len());
+        client.push_binary(content).map_err(Error::from)
+    } else {
+        let content = String::from_utf8(content).map_err(|_| {
+            Error::FlowBroken(flow::Error::BadMessageEncoding)
+        })?;
+        debug!("Send <= {}", content);
+        client.push(content).map_err(Error::from)
+    }
+}
+
+/// Delivers outputs of a performing action to a client.
+pub trait Emitter {
+    fn item(&mut self, value: Value) -> worker::Result<()>;
+    fn progress(&mut self, fraction: f64, note: Option<String>) -> worker::Result<()>;
+    fn done(&mut self) -> worker::Result<()>;
+
+    /// Sends bytes with their metadata, see `Output::binary`.
+    fn binary(&mut self, _meta: Value, _data: Vec<u8>) -> worker::Result<()> {
+        Err(worker::Error::Unimplemented)
+    }
+
+    /// Ends a page and waits until the client asks for the next one.
+    /// Only tasks running in background wait, others go on.
+    fn more(&mut self) -> worker::Result<()> {
+        Ok(())
+    }
+
+    /// Only tasks running in background can be canceled.
+    fn is_canceled(&self) -> bool {
+        false
+    }
+}
+
+/// Whether a line is a JSON value cut short, the rest is to come.
+fn is_cut(line: &[u8]) -> bool {
+    if line.iter().all(u8::is_ascii_whitespace) {
+        return false;
+    }
+    match serde_json::from_slice::<IgnoredAny>(line) {
+        Err(ref err) => err.is_eof(),
+        Ok(_) => false,
+    }
+}
+
+/// Pushes outputs straight to the flow, so they don't pile up
+/// while a worker performs.
+struct FlowEmitter<'a, R: Flow + 'a> {
+    id: TaskId,
+    request: (&'a str, &'a str),
+    client: &'a mut R,
+    codec: &'a Codec,
+    observer: &'a mut FnMut(&Output),
+    broken: Option<flow::Error>,
+}
+
+impl<'a, R: Flow> FlowEmitter<'a, R> {
+    fn emit(&mut self, result: TaskResult) -> worker::Result<()> {
+        let out = Output::new(self.id, result);
+        self.send(out)
+    }
+
+    fn send(&mut self, out: Output) -> worker::Result<()> {
+        if self.broken.is_some() {
+            return Err(worker::Error::Interrupted);
+        }
+        let out = out.echo(self.request.0, self.request.1);
+        match push_output(self.client, self.codec, &out) {
+            Ok(()) => {
+                (self.observer)(&out);
+                Ok(())
+            }
+            Err(Error::SerdeFailed(cause)) => Err(worker::Error::SerdeFailed(cause)),
+            Err(Error::FlowBroken(cause)) => {
+                self.broken = Some(cause);
+                Err(worker::Error::Interrupted)
+            }
+            Err(_) => Err(worker::Error::Interrupted),
+        }
+    }
+}
+
+impl<'a, R: Flow> Emitter for FlowEmitter<'a, R> {
+    fn item(&mut self, value: Value) -> worker::Result<()> {
+        self.emit(TaskResult::Item(value))
+    }
+
+    fn progress(&mut self, fraction: f64, note: Option<String>) -> worker::Result<()> {
+        self.emit(TaskResult::Progress { fraction: fraction, note: note })
+    }
+
+    fn done(&mut self) -> worker::Result<()> {
+        self.emit(TaskResult::Done)
+    }
+
+    fn binary(&mut self, meta: Value, data: Vec<u8>) -> worker::Result<()> {
+        let out = Output::binary(self.id, meta, data);
+        self.send(out)
+    }
+}
+
+/// Hands outputs of a background task to the session loop.
+struct TaskEmitter {
+    id: TaskId,
+    serial: usize,
+    sender: OutputSender,
+    canceled: Arc<AtomicBool>,
+    /// Requests of pages, closed when the task is dropped
+    next: Receiver<()>,
+    page_wait: Duration,
+}
+
+impl TaskEmitter {
+    fn emit(&mut self, result: TaskResult) -> worker::Result<()> {
+        let out = Output::new(self.id, result);
+        self.send(out)
+    }
+
+    fn send(&mut self, out: Output) -> worker::Result<()> {
+        if self.is_canceled() {
+            return Err(worker::Error::Interrupted);
+        }
+        self.sender.send((self.serial, out)).map_err(|_| {
+            worker::Error::Interrupted
+        })
+    }
+}
+
+impl Emitter for TaskEmitter {
+    fn item(&mut self, value: Value) -> worker::Result<()> {
+        self.emit(TaskResult::Item(value))
+    }
+
+    fn progress(&mut self, fraction: f64, note: Option<String>) -> worker::Result<()> {
+        self.emit(TaskResult::Progress { fraction: fraction, note: note })
+    }
+
+    fn done(&mut self) -> worker::Result<()> {
+        self.emit(TaskResult::Done)
+    }
+
+    fn binary(&mut self, meta: Value, data: Vec<u8>) -> worker::Result<()> {
+        let out = Output::binary(self.id, meta, data);
+        self.send(out)
+    }
+
+    fn more(&mut self) -> worker::Result<()> {
+        self.emit(TaskResult::More)?;
+        // Cancelling wakes the task too
+        match self.next.recv_timeout(self.page_wait) {
+            Ok(()) => (),
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(worker::Error::reject(code::TIMEOUT, "next page wasn't asked for"));
+            }
+            Err(RecvTimeoutError::Disconnected) => return Err(worker::Error::Interrupted),
+        }
+        if self.is_canceled() {
+            return Err(worker::Error::Interrupted);
+        }
+        Ok(())
+    }
+
+    fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst)
+    }
+}
+
 impl<T: Session, R: Flow> Deref for Context<T, R> {
     type Target = T;
 
     fn deref<'a>(&'a self) -> &'a T {
-        &self.session
+        self.session.as_ref().expect("session is taken")
     }
 }
 
 impl<T: Session, R: Flow> DerefMut for Context<T, R> {
     fn deref_mut<'a>(&'a mut self) -> &'a mut T {
-        &mut self.session
+        self.session.as_mut().expect("session is taken")
     }
 }
 
 impl<T: Session, R: Flow> Context<T, R> {
     pub fn new(client: R, session: T) -> Self {
+        let (outputs, collected) = mpsc::sync_channel(OUTPUT_CAPACITY);
+        let outputs = OutputSender {
+            sender: outputs,
+            gauge: Arc::new(Gauge::default()),
+        };
         Context {
+            who: client.who(),
+            session_id: 0,
             client: client,
-            session: session,
+            session: Some(session),
+            ndjson: false,
+            codec: Arc::new(JsonCodec::default()),
+            partial: Vec::new(),
+            detected: None,
+            pending: VecDeque::new(),
+            tasks: HashMap::new(),
+            serial: 0,
+            outputs: outputs,
+            collected: collected,
+            handles: Arc::new(()),
+            batch: Batch {
+                min: 1,
+                max: 1,
+                size: 1,
+            },
+            pool: None,
+            blocking: Duration::from_secs(0),
+            output_wait: Duration::from_secs(0),
+            period_blocking: Duration::from_secs(0),
+            period_started: Instant::now(),
+            cancel_grace: Duration::from_secs(5),
+            task_timeout: None,
+            page_wait: Duration::from_secs(300),
         }
     }
 
-    pub fn recv(
+    /// Sets the id a server has given to the session.
+    pub fn set_session_id(&mut self, id: usize) {
+        self.session_id = id;
+    }
+
+    pub fn session_id(&self) -> usize {
+        self.session_id
+    }
+
+    /// Takes the session out to keep it when the client has gone,
+    /// the context can't be used after it.
+    pub fn take_session(&mut self) -> Option<T> {
+        self.session.take()
+    }
+
+    /// Makes a handle which pushes notifications to the client.
+    /// Handles made before `set_output_capacity` are detached.
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            session_id: self.session_id,
+            sender: self.outputs.clone(),
+            _alive: self.handles.clone(),
+        }
+    }
+
+    /// Whether any handle is kept, notifications can come anytime then.
+    pub fn is_notified(&self) -> bool {
+        Arc::strong_count(&self.handles) > 1
+    }
+
+    /// Client of the session as its flow has told.
+    pub fn who(&self) -> &str {
+        &self.who
+    }
+
+    /// Treats every line of a received message as a separate request.
+    /// A line which can't be parsed is rejected alone. A request cut at
+    /// the end of a message is continued by the next one.
+    pub fn set_ndjson(&mut self, enabled: bool) {
+        self.ndjson = enabled;
+    }
+
+    /// Uses the codec for messages instead of JSON. Data after a complete
+    /// JSON value of a message is rejected unless the codec is lenient.
+    pub fn set_codec(&mut self, codec: Arc<Codec>) {
+        self.codec = codec;
+    }
+
+    /// Chooses the codec of the session by the first message of the
+    /// client, the first of `codecs` which recognizes it. The codec of
+    /// `set_codec` is kept if none does.
+    pub fn set_codec_detection(&mut self, codecs: Vec<Arc<Codec>>) {
+        self.detected = Some(codecs);
+    }
+
+    /// Lets outputs of background tasks be sent by `min` to `max` in
+    /// one frame, a line per output. Only clients which split frames
+    /// into lines can use it.
+    pub fn set_batching(&mut self, min: usize, max: usize) {
+        let min = min.max(1);
+        self.batch = Batch {
+            min: min,
+            max: max.max(min),
+            size: min,
+        };
+    }
+
+    /// Runs background tasks on the pool instead of own threads.
+    pub fn set_pool(&mut self, pool: Arc<Pool>) {
+        self.pool = Some(pool);
+    }
+
+    /// Fails background tasks running longer than `timeout` with
+    /// `TIMEOUT`, they're told to stop and their later outputs are
+    /// dropped. A limit of a task overrides it.
+    pub fn set_task_timeout(&mut self, timeout: Duration) {
+        self.task_timeout = Some(timeout);
+    }
+
+    /// Limits outputs of background tasks waiting to be sent, a task
+    /// which emits more blocks until the client takes them. Tasks never
+    /// run on the thread which sends, so it can't block itself. It has to
+    /// be called before any task is spawned.
+    pub fn set_output_capacity(&mut self, capacity: usize) {
+        let (outputs, collected) = mpsc::sync_channel(capacity.max(1));
+        self.outputs = OutputSender {
+            sender: outputs,
+            gauge: Arc::new(Gauge::default()),
+        };
+        self.collected = collected;
+    }
+
+    /// Sets how long a canceled task may run before it's abandoned.
+    pub fn set_cancel_grace(&mut self, grace: Duration) {
+        self.cancel_grace = grace;
+    }
+
+    /// Sets how long a paged task waits for the client to ask for the
+    /// next page, it fails with `TIMEOUT` then. It's 5 minutes by default.
+    pub fn set_page_wait(&mut self, wait: Duration) {
+        self.page_wait = wait;
+    }
+
+    /// Waits for the next message of the client.
+    pub fn recv(&mut self) -> Result<Inbound> {
+        loop {
+            if let Some(content) = self.pending.pop_front() {
+                return self.decode(&content);
+            }
+            if self.codec.is_binary() || self.detected.is_some() {
+                // Blocking pull carries text only
+                match self.client.try_pull()? {
+                    Pull::Empty => thread::sleep(Duration::from_millis(10)),
+                    pulled => self.accept(pulled)?,
+                }
+            } else {
+                let content = self.client.pull()?.ok_or(Error::ConnectionClosed)?;
+                self.enqueue(content);
+            }
+        }
+    }
+
+    /// Takes the next message of the client if it's already received.
+    pub fn try_recv(&mut self) -> Result<Option<Inbound>> {
+        if self.pending.is_empty() {
+            match self.client.try_pull()? {
+                Pull::Empty => return Ok(None),
+                pulled => self.accept(pulled)?,
+            }
+        }
+        match self.pending.pop_front() {
+            Some(content) => self.decode(&content).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn accept(&mut self, pulled: Pull) -> Result<()> {
+        match pulled {
+            Pull::Message(content) => {
+                self.detect(content.as_bytes());
+                self.enqueue(content);
+            }
+            Pull::Binary(content) => {
+                self.detect(&content);
+                self.pending.extend(self.codec.split(content));
+            }
+            Pull::Empty => (),
+            Pull::Closed => return Err(Error::ConnectionClosed),
+        }
+        Ok(())
+    }
+
+    /// Locks the session to the codec of the first message.
+    fn detect(&mut self, content: &[u8]) {
+        if let Some(codecs) = self.detected.take() {
+            match codecs.into_iter().find(|codec| codec.sniff(content)) {
+                Some(codec) => self.codec = codec,
+                None => debug!("Codec of {} isn't detected, the default one is kept", self.who),
+            }
+        }
+    }
+
+    fn enqueue(&mut self, content: String) {
+        if !self.ndjson {
+            self.pending.extend(self.codec.split(content.into_bytes()));
+            return;
+        }
+        let mut content = {
+            let mut joined = ::std::mem::replace(&mut self.partial, Vec::new());
+            joined.extend(content.into_bytes());
+            joined
+        };
+        let complete = content.iter().rposition(|byte| *byte == b'\n').map_or(0, |pos| pos + 1);
+        let tail = content.split_off(complete);
+        if is_cut(&tail) && tail.len() <= MAX_PARTIAL {
+            self.partial = tail;
+        } else {
+            if is_cut(&tail) {
+                debug!("Unterminated request of {} is too long", self.who);
+            }
+            content.extend(tail);
+        }
+        for line in content.split(|byte| *byte == b'\n') {
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                self.pending.extend(self.codec.split(line.to_vec()));
+            }
+        }
+    }
+
+    fn decode(&self, content: &[u8]) -> Result<Inbound> {
+        if self.codec.is_binary() {
+            debug!("Recv => {} bytes", content.len());
+        } else {
+            debug!("Recv => {}", String::from_utf8_lossy(content));
+        }
+        self.codec.decode(content)
+    }
+
+    pub fn stats(&self) -> FlowStats {
+        let mut stats = self.client.stats();
+        stats.blocking = self.blocking;
+        stats.output_wait = self.output_wait;
+        stats
+    }
+
+    /// Outputs of background tasks and notifications waiting to be
+    /// taken by `collect`, ones of tasks blocked on a full channel too.
+    pub fn pending_outputs(&self) -> usize {
+        self.outputs.gauge.pending.load(Ordering::SeqCst)
+    }
+
+    /// Time tasks have waited for room in the channel of outputs since
+    /// the last call, see `set_output_capacity`.
+    pub fn take_output_wait(&mut self) -> Duration {
+        let waited = self.outputs.gauge.take_wait();
+        self.output_wait += waited;
+        waited
+    }
+
+    /// Time the session thread has spent performing actions.
+    pub fn blocking_time(&self) -> Duration {
+        self.blocking
+    }
+
+    /// Time the session thread has spent performing actions since the
+    /// current period began, a new period begins every `period`.
+    pub fn period_blocking_time(&mut self, period: Duration) -> Duration {
+        if self.period_started.elapsed() >= period {
+            self.period_started = Instant::now();
+            self.period_blocking = Duration::from_secs(0);
+        }
+        self.period_blocking
+    }
+
+    /// Sends a redirect directive to the client and closes the flow.
+    pub fn redirect(&mut self, id: TaskId, url: &str) -> Result<()> {
+        self.send(Output::new(id, TaskResult::Redirect { url: url.to_owned() }))?;
+        self.client.close(REDIRECT_CODE, "redirect").map_err(Error::from)
+    }
+
+    /// Tells the client that the session ends and why.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<()> {
+        self.client.close(code, reason).map_err(Error::from)
+    }
+
+    /// Performs an action of a request which emits its outputs to the
+    /// client. The observer sees every output sent.
+    /// Fails if the flow is broken, a failure of the worker is returned
+    /// as is to be reported to the client.
+    pub fn perform(
         &mut self,
-    ) -> Result<Input> {
-        let content = self.client.pull()?.ok_or(Error::ConnectionClosed)?;
-        debug!("Recv => {}", content);
-        let input = serde_json::from_str(&content)?;
-        Ok(input)
+        id: TaskId,
+        request: (&str, &str),
+        action: &mut Action<T>,
+        payload: Value,
+        observer: &mut FnMut(&Output),
+    ) -> Result<worker::Result<Option<Box<Task>>>> {
+        let mut emitter = FlowEmitter {
+            id: id,
+            request: request,
+            client: &mut self.client,
+            codec: &*self.codec,
+            observer: observer,
+            broken: None,
+        };
+        let started = Instant::now();
+        let session = self.session.as_mut().expect("session is taken");
+        let outcome = (action.perform)(session, payload, &mut emitter);
+        let elapsed = started.elapsed();
+        self.blocking += elapsed;
+        self.period_blocking += elapsed;
+        match emitter.broken {
+            Some(cause) => Err(Error::FlowBroken(cause)),
+            None => Ok(outcome),
+        }
+    }
+
+    /// Runs a task on a separate thread, its outputs are taken
+    /// with `collect`. Tasks of a session run concurrently. A task
+    /// running with the same id is canceled, check `is_running` first.
+    pub fn spawn(&mut self, id: TaskId, task: Box<Task>) {
+        self.serial += 1;
+        let canceled = Arc::new(AtomicBool::new(false));
+        let (next, pages) = mpsc::channel();
+        let mut emitter = TaskEmitter {
+            id: id,
+            serial: self.serial,
+            sender: self.outputs.clone(),
+            canceled: canceled.clone(),
+            next: pages,
+            page_wait: self.page_wait,
+        };
+        let running = Running {
+            serial: self.serial,
+            started: Instant::now(),
+            canceled: canceled,
+            next: next,
+            canceling: None,
+            timeout: None,
+        };
+        if let Some(previous) = self.tasks.insert(id, running) {
+            previous.canceled.store(true, Ordering::SeqCst);
+        }
+        // A task logs in the span of its session
+        #[cfg(feature = "tracing")]
+        let span = tracing::Span::current();
+        let job = move || {
+            #[cfg(feature = "tracing")]
+            let _entered = span.enter();
+            let outcome = panic::catch_unwind(AssertUnwindSafe(|| task.run(&mut emitter)));
+            let reason = match outcome {
+                Ok(Ok(())) => return,
+                Ok(Err(err)) => err.failure(),
+                Err(_) => Failure::new(code::INTERNAL_ERROR, "task panicked"),
+            };
+            let out = Output::new(emitter.id, TaskResult::Fail(reason));
+            // The session could be gone already
+            emitter.sender.send((emitter.serial, out)).ok();
+        };
+        match self.pool {
+            Some(ref pool) => pool.execute(job),
+            None => {
+                thread::spawn(job);
+            }
+        }
+    }
+
+    /// Fails a background task with `TIMEOUT` if it runs longer than
+    /// `timeout`, it overrides the task timeout of the session. Returns
+    /// `false` if there is no such task.
+    pub fn limit(&mut self, id: TaskId, timeout: Duration) -> bool {
+        match self.tasks.get_mut(&id) {
+            Some(running) => {
+                running.timeout = Some(timeout);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether any task is running in background.
+    pub fn is_busy(&self) -> bool {
+        !self.tasks.is_empty()
+    }
+
+    /// Whether a task with the id is running in background.
+    pub fn is_running(&self, id: TaskId) -> bool {
+        self.tasks.contains_key(&id)
+    }
+
+    /// Asks a background task to stop, `collect` gives `Cancelled`
+    /// when it has. Returns `false` if there is no such task.
+    pub fn cancel(&mut self, id: TaskId) -> bool {
+        match self.tasks.get_mut(&id) {
+            Some(ref mut running) if running.canceling.is_none() => {
+                running.canceled.store(true, Ordering::SeqCst);
+                running.canceling = Some(Instant::now());
+                running.next.send(()).ok();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Lets a paged task produce its next page. Returns `false` if there
+    /// is no such task.
+    pub fn next(&mut self, id: TaskId) -> bool {
+        match self.tasks.get(&id) {
+            Some(running) if running.canceling.is_none() => running.next.send(()).is_ok(),
+            _ => false,
+        }
+    }
+
+    /// Takes an output of a background task or a notification if there
+    /// is one. Items of canceled tasks are dropped.
+    pub fn collect(&mut self) -> Option<Output> {
+        // Checked first, a task which floods outputs can't escape it
+        let task_timeout = self.task_timeout;
+        let expired = self.tasks
+            .iter()
+            .find(|&(_, running)| {
+                let timeout = running.timeout.or(task_timeout);
+                let expired = timeout.map_or(false, |timeout| running.started.elapsed() >= timeout);
+                running.canceling.is_none() && expired
+            })
+            .map(|(id, _)| *id);
+        if let Some(id) = expired {
+            if let Some(running) = self.tasks.remove(&id) {
+                running.canceled.store(true, Ordering::SeqCst);
+            }
+            debug!("Task {} timed out", id);
+            let failure = Failure::new(code::TIMEOUT, "task timed out");
+            return Some(Output::new(id, TaskResult::Fail(failure)));
+        }
+        while let Ok((serial, mut out)) = self.collected.try_recv() {
+            self.outputs.gauge.pending.fetch_sub(1, Ordering::SeqCst);
+            if serial == NOTIFY_SERIAL {
+                return Some(out);
+            }
+            let canceling = match self.tasks.get(&out.id) {
+                Some(running) if running.serial == serial => running.canceling.is_some(),
+                _ => {
+                    trace!("Drop output of abandoned task {}", out.id);
+                    continue;
+                }
+            };
+            if out.result.is_terminal() {
+                self.tasks.remove(&out.id);
+                if canceling {
+                    out.result = TaskResult::Cancelled;
+                }
+            } else if canceling {
+                continue;
+            }
+            return Some(out);
+        }
+        // Never wait for a task which ignores cancelling
+        let grace = self.cancel_grace;
+        let overdue = self.tasks
+            .iter()
+            .find(|&(_, running)| {
+                running.canceling.map_or(false, |since| since.elapsed() >= grace)
+            })
+            .map(|(id, _)| *id);
+        overdue.map(|id| {
+            debug!("Abandon canceled task {}", id);
+            self.tasks.remove(&id);
+            Output::new(id, TaskResult::Cancelled)
+        })
+    }
+
+    /// Takes outputs of background tasks for a single frame.
+    pub fn collect_batch(&mut self) -> Vec<Output> {
+        let mut batch = Vec::new();
+        while batch.len() < self.batch.size {
+            match self.collect() {
+                Some(out) => batch.push(out),
+                None => break,
+            }
+        }
+        self.batch.adapt(batch.len());
+        batch
     }
 
     pub fn send(&mut self, out: Output) -> Result<()> {
-        let content = serde_json::to_string(&out)?;
-        debug!("Send <= {}", content);
-        self.client.push(content).map_err(Error::from)
+        push_output(&mut self.client, &*self.codec, &out)
+    }
+
+    /// Sends outputs in one frame, a line per output, bytes of outputs
+    /// follow the frame. Outputs of a binary codec are sent in a frame
+    /// each.
+    pub fn send_batch(&mut self, outs: &[Output]) -> Result<()> {
+        if self.codec.is_binary() {
+            for out in outs {
+                push_output(&mut self.client, &*self.codec, out)?;
+            }
+            return Ok(());
+        }
+        let mut content = Vec::new();
+        for out in outs {
+            if !content.is_empty() {
+                content.push(b'\n');
+            }
+            content.extend(self.codec.encode(out)?);
+        }
+        push_frame(&mut self.client, &*self.codec, content)?;
+        for out in outs {
+            push_attachment(&mut self.client, out)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Session, R: Flow> Drop for Context<T, R> {
+    fn drop(&mut self) {
+        for running in self.tasks.values() {
+            running.canceled.store(true, Ordering::SeqCst);
+        }
     }
 }
@@ -0,0 +1,99 @@
+//! Helpers to exercise services without a real connection.
+//!
+//! `run_request` performs an `Input` with a session through a
+//! `MemoryFlow` and returns the outputs which a client would get, so
+//! items of a worker can be asserted in a unit test.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+use serde_json;
+use flow::{self, Flow, Pull};
+use server::{Suite, SessionLoop, Turn};
+use session::{Session, Input, Output};
+
+/// Flow which keeps messages in memory. Messages of the client are
+/// queued with `send`, the session takes them one by one and sees
+/// the client closed when the queue is empty.
+pub struct MemoryFlow {
+    who: String,
+    inbound: VecDeque<String>,
+    outbound: VecDeque<String>,
+}
+
+impl MemoryFlow {
+    pub fn new(who: &str) -> Self {
+        MemoryFlow {
+            who: who.to_owned(),
+            inbound: VecDeque::new(),
+            outbound: VecDeque::new(),
+        }
+    }
+
+    /// Queues a message of the client.
+    pub fn send(&mut self, content: String) {
+        self.inbound.push_back(content);
+    }
+
+    /// Takes the oldest frame which the session has pushed.
+    pub fn recv(&mut self) -> Option<String> {
+        self.outbound.pop_front()
+    }
+
+    /// Takes every output which the session has pushed, a frame can
+    /// carry a batch of outputs a line each.
+    pub fn outputs(&mut self) -> Vec<Output> {
+        let mut outputs = Vec::new();
+        while let Some(frame) = self.recv() {
+            for line in frame.lines().filter(|line| !line.trim().is_empty()) {
+                match serde_json::from_str(line) {
+                    Ok(output) => outputs.push(output),
+                    Err(err) => warn!("Can't read an output of {}: {}", self.who, err),
+                }
+            }
+        }
+        outputs
+    }
+}
+
+impl Flow for MemoryFlow {
+    fn who(&self) -> String {
+        self.who.clone()
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        Ok(self.inbound.pop_front())
+    }
+
+    fn try_pull(&mut self) -> flow::Result<Pull> {
+        Ok(match self.inbound.pop_front() {
+            Some(content) => Pull::Message(content),
+            None => Pull::Closed,
+        })
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        self.outbound.push_back(content);
+        Ok(())
+    }
+}
+
+/// Performs a request with the session and returns every output it
+/// has got, background tasks are waited for. The suite must use a text
+/// codec.
+pub fn run_request<T: Session>(suite: &Suite<T>, session: T, input: Input) -> Vec<Output> {
+    let mut flow = MemoryFlow::new("memory");
+    let content = serde_json::to_string(&input).expect("an input is serializable");
+    flow.send(content);
+    {
+        let mut session = SessionLoop::with_session(suite, &mut flow, session);
+        loop {
+            match session.turn(true) {
+                Turn::Progressed => (),
+                Turn::Idle => thread::sleep(Duration::from_millis(1)),
+                Turn::Ended => break,
+            }
+        }
+    }
+    flow.outputs()
+}
@@ -0,0 +1,86 @@
+//! Service which helps clients to estimate a clock offset and a round-trip.
+//!
+//! It doesn't require any rights. Register it to make it available:
+//! `suite.register("time", TimeService);`
+//!
+//! Actions:
+//!
+//! * `now` with a `null` payload returns `{"timestamp": ...}`
+//! * `sync` with `{"client_time": t0}` returns `{"client_time": t0,
+//!   "server_received": t1, "server_sent": t2}`, so the client which gets
+//!   it at `t3` computes `offset = ((t1 - t0) + (t2 - t3)) / 2` and
+//!   `rtt = (t3 - t0) - (t2 - t1)`.
+//!
+//! All times are milliseconds since the unix epoch with a fractional part.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use session::Session;
+use service::{self, Service, Action};
+use worker::{self, Worker};
+
+pub fn timestamp() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_secs() as f64 * 1000.0 + dur.subsec_nanos() as f64 / 1_000_000.0)
+        .unwrap_or(0.0)
+}
+
+pub struct TimeService;
+
+impl<T: Session> Service<T> for TimeService {
+    fn route(&self, action: &str) -> service::Result<Action<T>> {
+        match action {
+            "now" => Ok(Action::from_worker(NowWorker)),
+            "sync" => Ok(Action::from_worker(SyncWorker)),
+            _ => Err(service::Error::ActionNotFound),
+        }
+    }
+
+    fn actions(&self) -> Vec<&'static str> {
+        vec!["now", "sync"]
+    }
+}
+
+#[derive(Serialize)]
+pub struct Now {
+    pub timestamp: f64,
+}
+
+pub struct NowWorker;
+
+impl<T: Session> Worker<T> for NowWorker {
+    type In = ();
+    type Out = Now;
+
+    fn perform(&mut self, _: &mut T, _: Self::In) -> worker::Result<Self::Out> {
+        Ok(Now { timestamp: timestamp() })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SyncRequest {
+    pub client_time: f64,
+}
+
+#[derive(Serialize)]
+pub struct SyncResponse {
+    pub client_time: f64,
+    pub server_received: f64,
+    pub server_sent: f64,
+}
+
+pub struct SyncWorker;
+
+impl<T: Session> Worker<T> for SyncWorker {
+    type In = SyncRequest;
+    type Out = SyncResponse;
+
+    fn perform(&mut self, _: &mut T, request: Self::In) -> worker::Result<Self::Out> {
+        let server_received = timestamp();
+        Ok(SyncResponse {
+            client_time: request.client_time,
+            server_received: server_received,
+            server_sent: timestamp(),
+        })
+    }
+}
@@ -1,6 +1,9 @@
 use std::borrow::Cow;
+use std::marker::PhantomData;
+use serde::Serialize;
 use serde_json;
-use session::Session;
+use futures::Future;
+use session::{Session, Emitter, Failure, code};
 use permission;
 
 #[derive(Debug, Fail)]
@@ -11,12 +14,61 @@ pub enum Error {
     SysFault,
     #[fail(display = "unimplemented perform")]
     Unimplemented,
-    #[fail(display = "permission error")]
+    #[fail(display = "output interrupted")]
+    Interrupted,
+    #[fail(display = "permission error: {}", _0)]
     PermissionWrong(#[cause] permission::Error),
     #[fail(display = "serde error")]
     SerdeFailed(#[cause] serde_json::Error),
     #[fail(display = "worker error: {}", _0)]
     Other(Cow<'static, str>),
+    /// Asks the client to reconnect to another node
+    #[fail(display = "redirect to {}", _0)]
+    Redirect(String),
+    /// Fails the request with a failure of the worker's choice
+    #[fail(display = "{}", _0)]
+    Rejected(Failure),
+    /// Ends the session, the flow is closed with the code and the reason
+    /// (e.g. `4401`, "re-authenticate"). Only actions performed on the
+    /// session thread can close it.
+    #[fail(display = "close session: {} {}", code, reason)]
+    CloseSession { code: u16, reason: String },
+}
+
+impl Error {
+    /// Rejects a request with a coded failure, e.g.
+    /// `Error::reject("NOT_FOUND", "user 42 missing")`.
+    pub fn reject<M: Into<String>>(code: &str, message: M) -> Self {
+        Error::Rejected(Failure::new(code, message))
+    }
+
+    /// Ends the session with a close code of the application, websocket
+    /// clients see it in their close event.
+    pub fn close<R: Into<String>>(code: u16, reason: R) -> Self {
+        Error::CloseSession {
+            code: code,
+            reason: reason.into(),
+        }
+    }
+
+    /// Failure which the client gets for the error.
+    pub fn failure(&self) -> Failure {
+        match *self {
+            Error::Rejected(ref failure) => failure.clone(),
+            Error::PermissionWrong(ref cause) => Failure::new(code::ACCESS_DENIED, cause.to_string()),
+            Error::SerdeFailed(ref cause) => Failure::new(code::BAD_REQUEST, cause.to_string()),
+            Error::Unimplemented => Failure::new(code::UNIMPLEMENTED, self.to_string()),
+            Error::Other(ref reason) => Failure::new(code::WORKER_ERROR, reason.as_ref()),
+            _ => Failure::new(code::INTERNAL_ERROR, self.to_string()),
+        }
+    }
+}
+
+/// Errors of workers which convert into failures are rejects.
+impl From<Failure> for Error {
+    fn from(failure: Failure) -> Self {
+        Error::Rejected(failure)
+    }
 }
 
 impl From<permission::Error> for Error {
@@ -51,3 +103,153 @@ pub trait Worker<T: Session> {
 
     fn perform(&mut self, _: &mut T, _: Self::In) -> Result<Self::Out>;
 }
+
+/// Sends items of a streaming worker to a client one by one.
+pub struct Stream<'a, O> {
+    emitter: &'a mut Emitter,
+    marker: PhantomData<O>,
+}
+
+impl<'a, O: Serialize> Stream<'a, O> {
+    pub fn new(emitter: &'a mut Emitter) -> Self {
+        Stream {
+            emitter: emitter,
+            marker: PhantomData,
+        }
+    }
+
+    /// Sends an item immediately. Fails with `Interrupted` if the client
+    /// is gone, a worker should stop then.
+    pub fn emit(&mut self, item: O) -> Result<()> {
+        let value = serde_json::to_value(item)?;
+        self.emitter.item(value)
+    }
+
+    /// Sends bytes as they are in a binary frame, the client gets
+    /// the metadata first as a `binary` event.
+    pub fn binary<M: Serialize>(&mut self, meta: M, data: Vec<u8>) -> Result<()> {
+        let meta = serde_json::to_value(meta)?;
+        self.emitter.binary(meta, data)
+    }
+
+    /// Reports which part of the work is done, from `0.0` to `1.0`.
+    /// Clients get it as a `progress` event, not as an item.
+    pub fn progress(&mut self, fraction: f64, note: Option<String>) -> Result<()> {
+        self.emitter.progress(fraction.max(0.0).min(1.0), note)
+    }
+
+    /// Ends a page of a background job and waits until the client asks
+    /// for the next one. Fails with `Interrupted` if the task is canceled.
+    pub fn more(&mut self) -> Result<()> {
+        self.emitter.more()
+    }
+
+    /// Whether the client has canceled the task. A long worker should
+    /// check it and return soon, items of a canceled task are dropped.
+    pub fn is_canceled(&self) -> bool {
+        self.emitter.is_canceled()
+    }
+}
+
+/// Item of a bulk operation, see `Stream::bulk`. Clients tell items
+/// apart by `outcome`: `ok`, `failed` or the final `summary`.
+#[derive(Serialize, Debug)]
+#[serde(tag = "outcome", rename_all = "lowercase")]
+pub enum Bulk<K, V> {
+    Ok { key: K, value: V },
+    Failed { key: K, failure: Failure },
+    Summary { ok: usize, failed: usize },
+}
+
+impl<'a, K: Serialize, V: Serialize> Stream<'a, Bulk<K, V>> {
+    /// Performs `f` for every key and sends its outcome as soon as it's
+    /// known, then a summary. A failed key doesn't stop the others, the
+    /// whole operation stops with `Interrupted` if the task is canceled.
+    /// Background tasks wait while the client falls behind.
+    pub fn bulk<I, F>(&mut self, keys: I, mut f: F) -> Result<()>
+    where
+        I: IntoIterator<Item = K>,
+        F: FnMut(&K) -> Result<V>,
+    {
+        let (mut ok, mut failed) = (0, 0);
+        for key in keys {
+            if self.is_canceled() {
+                return Err(Error::Interrupted);
+            }
+            let outcome = match f(&key) {
+                Ok(value) => {
+                    ok += 1;
+                    Bulk::Ok { key: key, value: value }
+                }
+                Err(err) => {
+                    failed += 1;
+                    Bulk::Failed { key: key, failure: err.failure() }
+                }
+            };
+            self.emit(outcome)?;
+        }
+        self.emit(Bulk::Summary { ok: ok, failed: failed })
+    }
+}
+
+/// Worker which emits any number of items. The client gets `done`
+/// after the last one.
+pub trait StreamWorker<T: Session> {
+    type In;
+    type Out;
+
+    fn perform(&mut self, _: &mut T, _: Self::In, _: &mut Stream<Self::Out>) -> Result<()>;
+}
+
+/// Part of a background worker which runs on its own thread.
+pub trait Job<O>: Send {
+    fn run(self: Box<Self>, stream: &mut Stream<O>) -> Result<()>;
+}
+
+impl<O, F> Job<O> for F
+where
+    F: FnOnce(&mut Stream<O>) -> Result<()> + Send,
+{
+    fn run(self: Box<Self>, stream: &mut Stream<O>) -> Result<()> {
+        (*self)(stream)
+    }
+}
+
+/// Worker for long tasks which can be canceled by the client.
+///
+/// `prepare` takes what the task needs from the session on the session
+/// thread, the returned job emits items from a separate thread and
+/// the session keeps reading messages of the client meanwhile.
+pub trait BackgroundWorker<T: Session> {
+    type In;
+    type Out;
+
+    fn prepare(&mut self, _: &mut T, _: Self::In) -> Result<Box<Job<Self::Out>>>;
+}
+
+/// Worker which gives items page by page, they're taken from the
+/// iterator only when the client asks for them. The client gets `more`
+/// after every full page and `done` after the last one, which can be
+/// empty. A task timeout of the suite counts time spent waiting, and
+/// a task whose client doesn't ask for the next page within the page
+/// wait of the suite fails with `TIMEOUT`, releasing its thread.
+pub trait PagedWorker<T: Session> {
+    type In;
+    type Out;
+
+    fn page_size(&self) -> usize {
+        20
+    }
+
+    fn prepare(&mut self, _: &mut T, _: Self::In) -> Result<Box<Iterator<Item = Self::Out> + Send>>;
+}
+
+/// Worker which waits for I/O (a database, HTTP) without blocking
+/// the session thread. The future is driven in background and the item
+/// it resolves to is sent to the client.
+pub trait AsyncWorker<T: Session> {
+    type In;
+    type Out;
+
+    fn perform(&mut self, _: &mut T, _: Self::In) -> Box<Future<Item = Self::Out, Error = Error> + Send>;
+}
@@ -0,0 +1,68 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde_json::Value;
+use mould::audit::AuditLog;
+use mould::server::process_session;
+use common::{echo, flow, suite};
+
+/// Lines of the audit log.
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink {
+    /// Waits for `count` entries written by the thread of the log.
+    fn entries(&self, count: usize) -> Vec<Value> {
+        let started = Instant::now();
+        loop {
+            let entries: Vec<Value> = String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            if entries.len() >= count || started.elapsed() > Duration::from_secs(1) {
+                return entries;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+#[test]
+fn entries_carry_the_request_id_and_are_redacted() {
+    let sink = Sink::default();
+    let log = AuditLog::new(sink.clone())
+        .service("echo")
+        .redact(Box::new(|entry: &mut Value| {
+            if let Some(password) = entry.pointer_mut("/payload/password") {
+                *password = json!("***");
+            }
+        }));
+    let mut suite = suite();
+    suite.set_audit_log(log);
+    let mut flow = flow(&[echo(5, json!({"password": "secret"}))]);
+    process_session(&suite, &mut flow);
+    let entries = sink.entries(3);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0]["id"], json!(5));
+    assert_eq!(entries[0]["payload"], json!({"password": "***"}));
+    assert!(entries[1..].iter().all(|entry| entry["id"] == json!(5)));
+}
@@ -0,0 +1,154 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use mould::flow::{self, Flow, FlowStats, Pull};
+use mould::prelude::*;
+use mould::server::{process_session, Metrics, SessionLoop, Suite, Turn};
+use mould::testing::MemoryFlow;
+use common::{flow, Plain};
+
+/// Emits `count` numbers in background.
+struct Numbers;
+
+impl BackgroundWorker<Plain> for Numbers {
+    type In = u64;
+    type Out = u64;
+
+    fn prepare(&mut self, _: &mut Plain, count: u64) -> worker::Result<Box<Job<u64>>> {
+        let job = move |stream: &mut Stream<u64>| {
+            for n in 0..count {
+                stream.emit(n)?;
+            }
+            Ok(())
+        };
+        Ok(Box::new(job))
+    }
+}
+
+struct NumbersService;
+
+impl service::Service<Plain> for NumbersService {
+    fn route(&self, _: &str) -> service::Result<Action<Plain>> {
+        Ok(Action::from_background_worker(Numbers))
+    }
+}
+
+/// Lines of every frame the session has sent for 8 numbers, the task
+/// is done before the session takes its outputs.
+fn frames(suite: &Suite<Plain>) -> Vec<usize> {
+    let request = json!({"id": 1, "service": "numbers", "action": "count", "payload": 8});
+    let mut flow = flow(&[request.to_string()]);
+    {
+        let mut session = SessionLoop::new(suite, &mut flow).expect("session is built");
+        session.turn(false);
+        thread::sleep(Duration::from_millis(200));
+        while session.turn(false) != Turn::Ended {}
+    }
+    let mut frames = Vec::new();
+    while let Some(frame) = flow.recv() {
+        frames.push(frame.lines().count());
+    }
+    frames
+}
+
+#[test]
+fn outputs_are_sent_one_by_one_without_batching() {
+    let mut suite = Suite::new(mould::session::DefaultBuilder);
+    suite.register("numbers", NumbersService);
+    assert_eq!(frames(&suite), vec![1; 9]);
+}
+
+#[test]
+fn outputs_are_batched_with_batching() {
+    let mut suite = Suite::new(mould::session::DefaultBuilder);
+    suite.register("numbers", NumbersService);
+    suite.set_batching(4, 4);
+    assert_eq!(frames(&suite), vec![4, 4, 1]);
+}
+
+/// Client which takes 2ms to receive a frame, whatever its size.
+struct SlowFlow(MemoryFlow);
+
+impl Flow for SlowFlow {
+    fn who(&self) -> String {
+        self.0.who()
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        self.0.pull()
+    }
+
+    fn try_pull(&mut self) -> flow::Result<Pull> {
+        self.0.try_pull()
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        thread::sleep(Duration::from_millis(2));
+        self.0.push(content)
+    }
+}
+
+/// Frames and time it takes to stream 500 numbers to a slow client.
+fn slow_client(suite: &Suite<Plain>) -> (usize, Duration) {
+    let request = json!({"id": 1, "service": "numbers", "action": "count", "payload": 500});
+    let mut flow = SlowFlow(flow(&[request.to_string()]));
+    let begun = Instant::now();
+    process_session(suite, &mut flow);
+    let elapsed = begun.elapsed();
+    let mut frames = 0;
+    while flow.0.recv().is_some() {
+        frames += 1;
+    }
+    (frames, elapsed)
+}
+
+#[test]
+fn batching_keeps_up_with_slow_client() {
+    let mut suite = Suite::new(mould::session::DefaultBuilder);
+    suite.register("numbers", NumbersService);
+    let (single_frames, single_time) = slow_client(&suite);
+    suite.set_batching(1, 64);
+    let (batched_frames, batched_time) = slow_client(&suite);
+    assert_eq!(single_frames, 501);
+    assert!(batched_frames < single_frames / 4);
+    assert!(batched_time < single_time / 2);
+}
+
+/// Most outputs seen pending, waits of tasks reported with deliveries
+/// and the stats of the closed session.
+#[derive(Clone, Default)]
+struct Channel(Arc<Mutex<(usize, Duration, FlowStats)>>);
+
+impl Metrics for Channel {
+    fn outputs_delivered(&self, _: usize, pending: usize, waited: Duration) {
+        let mut seen = self.0.lock().unwrap();
+        seen.0 = seen.0.max(pending);
+        seen.1 += waited;
+    }
+
+    fn session_closed(&self, _: usize, stats: FlowStats) {
+        self.0.lock().unwrap().2 = stats;
+    }
+}
+
+#[test]
+fn tasks_wait_for_room_of_slow_client() {
+    let mut suite = Suite::new(mould::session::DefaultBuilder);
+    suite.register("numbers", NumbersService);
+    suite.set_output_capacity(4);
+    let channel = Channel::default();
+    suite.set_metrics(channel.clone());
+    let (frames, _) = slow_client(&suite);
+    assert_eq!(frames, 501);
+    let (pending, waited, stats) = *channel.0.lock().unwrap();
+    // The task blocked on the full channel holds one more
+    assert!(pending <= 4 + 1, "{} outputs pending over the capacity", pending);
+    assert!(waited > Duration::from_millis(100), "tasks waited {:?} only", waited);
+    assert_eq!(stats.output_wait, waited);
+}
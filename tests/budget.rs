@@ -0,0 +1,82 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use serde_json::Value;
+use mould::flow::{self, Flow, FlowStats};
+use mould::server::Metrics;
+use mould::server::process_session;
+use mould::session::Output;
+use common::{events, suite, Plain};
+
+/// Client which pauses before some of its messages.
+struct Pausing {
+    inbound: VecDeque<(Duration, String)>,
+    outbound: Vec<String>,
+}
+
+impl Flow for Pausing {
+    fn who(&self) -> String {
+        "pausing".into()
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        Ok(self.inbound.pop_front().map(|(pause, content)| {
+            thread::sleep(pause);
+            content
+        }))
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        self.outbound.push(content);
+        Ok(())
+    }
+}
+
+/// Keeps the stats of the last closed session.
+#[derive(Clone, Default)]
+struct Closed(Arc<Mutex<FlowStats>>);
+
+impl Metrics for Closed {
+    fn session_closed(&self, _: usize, stats: FlowStats) {
+        *self.0.lock().unwrap() = stats;
+    }
+}
+
+#[test]
+fn budget_is_renewed_every_period() {
+    let mut suite = suite();
+    suite.register_fn("slow", "slow", |_: &mut Plain, payload: Value| {
+        thread::sleep(Duration::from_millis(40));
+        Ok(payload)
+    });
+    suite.set_compute_budget(Duration::from_millis(30), Duration::from_millis(150));
+    let closed = Closed::default();
+    suite.set_metrics(closed.clone());
+    let slow = |id: u64| json!({"id": id, "service": "slow", "action": "slow", "payload": null}).to_string();
+    let mut flow = Pausing {
+        inbound: VecDeque::from(vec![
+            (Duration::from_millis(0), slow(1)),
+            (Duration::from_millis(0), slow(2)),
+            (Duration::from_millis(200), slow(3)),
+        ]),
+        outbound: Vec::new(),
+    };
+    process_session(&suite, &mut flow);
+    let outputs: Vec<Output> = flow.outbound
+        .iter()
+        .map(|frame| serde_json::from_str(frame).unwrap())
+        .collect();
+    assert_eq!(
+        events(&outputs),
+        vec![(1, "item".into()), (1, "done".into()), (2, "fail".into()), (3, "item".into()), (3, "done".into())]
+    );
+    assert_eq!(serde_json::to_value(&outputs[2].result).unwrap()["data"]["code"], json!("OVER_BUDGET"));
+    assert!(closed.0.lock().unwrap().blocking >= Duration::from_millis(80));
+}
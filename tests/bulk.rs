@@ -0,0 +1,62 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::session::DefaultBuilder;
+use common::{events, flow, Plain};
+
+/// Halves even numbers of its payload, odd ones fail.
+struct Halver;
+
+impl BackgroundWorker<Plain> for Halver {
+    type In = Vec<u32>;
+    type Out = Bulk<u32, u32>;
+
+    fn prepare(&mut self, _: &mut Plain, numbers: Vec<u32>) -> worker::Result<Box<Job<Bulk<u32, u32>>>> {
+        let job = move |stream: &mut Stream<Bulk<u32, u32>>| {
+            stream.bulk(numbers, |number| {
+                if number % 2 == 0 {
+                    Ok(number / 2)
+                } else {
+                    Err(worker::Error::reject("ODD", "can't halve"))
+                }
+            })
+        };
+        Ok(Box::new(job))
+    }
+}
+
+struct HalveService;
+
+impl service::Service<Plain> for HalveService {
+    fn route(&self, _: &str) -> service::Result<Action<Plain>> {
+        Ok(Action::from_background_worker(Halver))
+    }
+}
+
+#[test]
+fn bulk_sends_outcome_of_every_key_and_summary() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("halve", HalveService);
+    let request = json!({"id": 1, "service": "halve", "action": "", "payload": [4, 3, 8]});
+    let mut flow = flow(&[request.to_string()]);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(
+        events(&outputs),
+        vec![(1, "item".into()), (1, "item".into()), (1, "item".into()), (1, "item".into()), (1, "done".into())]
+    );
+    let items: Vec<_> = outputs[..4]
+        .iter()
+        .map(|out| serde_json::to_value(&out.result).unwrap()["data"].clone())
+        .collect();
+    assert_eq!(items[0], json!({"outcome": "ok", "key": 4, "value": 2}));
+    assert_eq!(items[1]["outcome"], json!("failed"));
+    assert_eq!(items[1]["failure"]["code"], json!("ODD"));
+    assert_eq!(items[2], json!({"outcome": "ok", "key": 8, "value": 4}));
+    assert_eq!(items[3], json!({"outcome": "summary", "ok": 2, "failed": 1}));
+}
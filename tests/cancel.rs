@@ -0,0 +1,87 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::session::DefaultBuilder;
+use common::{events, flow, Plain};
+
+/// Works until it's canceled, or for `ms` if it ignores cancelling.
+struct Sleeper {
+    cooperative: bool,
+}
+
+impl BackgroundWorker<Plain> for Sleeper {
+    type In = u64;
+    type Out = ();
+
+    fn prepare(&mut self, _: &mut Plain, ms: u64) -> worker::Result<Box<Job<()>>> {
+        let cooperative = self.cooperative;
+        let job = move |stream: &mut Stream<()>| {
+            let begun = Instant::now();
+            while begun.elapsed() < Duration::from_millis(ms) {
+                if cooperative && stream.is_canceled() {
+                    return Ok(());
+                }
+                thread::sleep(Duration::from_millis(5));
+            }
+            Ok(())
+        };
+        Ok(Box::new(job))
+    }
+}
+
+struct SleepService;
+
+impl service::Service<Plain> for SleepService {
+    fn route(&self, action: &str) -> service::Result<Action<Plain>> {
+        let cooperative = action == "cooperative";
+        Ok(Action::from_background_worker(Sleeper { cooperative: cooperative }))
+    }
+}
+
+/// Starts a task of the action and cancels it at once.
+fn cancel(suite: &Suite<Plain>, action: &str) -> Vec<(usize, String)> {
+    let request = json!({"id": 1, "service": "sleep", "action": action, "payload": 2000});
+    let cancel = json!({"event": "cancel", "id": 1});
+    let mut flow = flow(&[request.to_string(), cancel.to_string()]);
+    process_session(suite, &mut flow);
+    events(&flow.outputs())
+}
+
+#[test]
+fn cooperative_task_confirms_cancel() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("sleep", SleepService);
+    let begun = Instant::now();
+    assert_eq!(cancel(&suite, "cooperative"), vec![(1, "cancelled".into())]);
+    assert!(begun.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn stubborn_task_is_abandoned_after_grace() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("sleep", SleepService);
+    suite.set_cancel_grace(Duration::from_millis(100));
+    let begun = Instant::now();
+    assert_eq!(cancel(&suite, "stubborn"), vec![(1, "cancelled".into())]);
+    let elapsed = begun.elapsed();
+    assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(1));
+}
+
+#[test]
+fn duplicate_id_of_running_task_is_rejected() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("sleep", SleepService);
+    let request = json!({"id": 1, "service": "sleep", "action": "stubborn", "payload": 100});
+    let mut flow = flow(&[request.to_string(), request.to_string()]);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(events(&outputs), vec![(1, "fail".into()), (1, "done".into())]);
+    assert_eq!(serde_json::to_value(&outputs[0].result).unwrap()["data"]["code"], json!("CONFLICT"));
+}
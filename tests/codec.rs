@@ -0,0 +1,229 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+#[cfg(feature = "msgpack")]
+extern crate rmp_serde;
+
+mod common;
+
+use std::collections::VecDeque;
+use serde_json::Value;
+use mould::codec::{Codec, JsonCodec};
+use mould::flow::{self, Flow, Pull};
+use mould::server::process_session;
+use mould::session::{Inbound, Output};
+use common::{echo, events, flow, suite};
+
+#[test]
+fn ndjson_frame_carries_two_requests() {
+    let mut suite = suite();
+    suite.set_ndjson(true);
+    let frame = format!("{}\n{}", echo(1, json!("a")), echo(2, json!("b")));
+    let mut flow = flow(&[frame]);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(
+        events(&outputs),
+        vec![(1, "item".into()), (1, "done".into()), (2, "item".into()), (2, "done".into())]
+    );
+    assert_eq!(serde_json::to_value(&outputs[0].result).unwrap()["data"], json!("a"));
+    assert_eq!(serde_json::to_value(&outputs[2].result).unwrap()["data"], json!("b"));
+}
+
+#[test]
+fn ndjson_request_continues_in_next_frame() {
+    let mut suite = suite();
+    suite.set_ndjson(true);
+    let second = echo(2, json!("b"));
+    let (head, tail) = second.split_at(second.len() / 2);
+    let frames = [format!("{}\n{}", echo(1, json!("a")), head), format!("{}\n{}", tail, echo(3, json!("c")))];
+    let mut flow = flow(&frames);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(
+        events(&outputs),
+        vec![
+            (1, "item".into()),
+            (1, "done".into()),
+            (2, "item".into()),
+            (2, "done".into()),
+            (3, "item".into()),
+            (3, "done".into()),
+        ]
+    );
+    assert_eq!(serde_json::to_value(&outputs[2].result).unwrap()["data"], json!("b"));
+}
+
+#[test]
+fn trailing_data_is_rejected_by_default() {
+    let suite = suite();
+    let mut flow = flow(&[format!("{} garbage", echo(1, json!("a"))), echo(2, json!("b"))]);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(events(&outputs), vec![(1, "fail".into()), (2, "item".into()), (2, "done".into())]);
+    assert_eq!(serde_json::to_value(&outputs[0].result).unwrap()["data"]["code"], json!("BAD_REQUEST"));
+}
+
+#[test]
+fn trailing_data_is_ignored_when_lenient() {
+    let mut suite = suite();
+    suite.set_codec(JsonCodec::new(true));
+    let mut flow = flow(&[format!("{} garbage", echo(1, json!("a"))), echo(2, json!("b"))]);
+    process_session(&suite, &mut flow);
+    assert_eq!(
+        events(&flow.outputs()),
+        vec![(1, "item".into()), (1, "done".into()), (2, "item".into()), (2, "done".into())]
+    );
+}
+
+#[test]
+fn trailing_data_of_batch_is_ignored_when_lenient() {
+    let mut suite = suite();
+    suite.set_codec(JsonCodec::new(true));
+    let batch = format!("[{}, {}] garbage", echo(1, json!("a")), echo(2, json!("b")));
+    let mut flow = flow(&[batch]);
+    process_session(&suite, &mut flow);
+    assert_eq!(
+        events(&flow.outputs()),
+        vec![(1, "item".into()), (1, "done".into()), (2, "item".into()), (2, "done".into())]
+    );
+}
+
+#[test]
+fn leniency_is_kept_by_a_configured_codec() {
+    let mut lenient = suite();
+    lenient.set_codec(JsonCodec::new(true).big_ints_as_strings(true));
+    let mut strict = suite();
+    strict.set_codec(JsonCodec::default().big_ints_as_strings(true));
+    let frames = [format!("{} garbage", echo(1, json!("a")))];
+
+    let mut flow_of_lenient = flow(&frames);
+    process_session(&lenient, &mut flow_of_lenient);
+    assert_eq!(events(&flow_of_lenient.outputs()), vec![(1, "item".into()), (1, "done".into())]);
+    let mut flow_of_strict = flow(&frames);
+    process_session(&strict, &mut flow_of_strict);
+    assert_eq!(events(&flow_of_strict.outputs()), vec![(1, "fail".into())]);
+}
+
+/// Client which sends text and binary frames.
+struct Frames {
+    inbound: VecDeque<Pull>,
+    texts: Vec<String>,
+    binaries: Vec<Vec<u8>>,
+}
+
+impl Frames {
+    fn new(inbound: Vec<Pull>) -> Self {
+        Frames {
+            inbound: inbound.into_iter().collect(),
+            texts: Vec::new(),
+            binaries: Vec::new(),
+        }
+    }
+}
+
+impl Flow for Frames {
+    fn who(&self) -> String {
+        "frames".into()
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        match self.inbound.pop_front() {
+            Some(Pull::Message(content)) => Ok(Some(content)),
+            _ => Ok(None),
+        }
+    }
+
+    fn try_pull(&mut self) -> flow::Result<Pull> {
+        Ok(self.inbound.pop_front().unwrap_or(Pull::Closed))
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        self.texts.push(content);
+        Ok(())
+    }
+
+    fn push_binary(&mut self, content: Vec<u8>) -> flow::Result<()> {
+        self.binaries.push(content);
+        Ok(())
+    }
+}
+
+/// JSON in binary frames after a zero byte.
+struct Tagged;
+
+impl Codec for Tagged {
+    fn is_binary(&self) -> bool {
+        true
+    }
+
+    fn decode(&self, content: &[u8]) -> mould::session::Result<Inbound> {
+        JsonCodec::default().decode(&content[1..])
+    }
+
+    fn encode(&self, out: &Output) -> mould::session::Result<Vec<u8>> {
+        let mut content = vec![0];
+        content.extend(serde_json::to_vec(out)?);
+        Ok(content)
+    }
+
+    fn sniff(&self, content: &[u8]) -> bool {
+        content.first() == Some(&0)
+    }
+}
+
+fn tagged(request: String) -> Vec<u8> {
+    let mut content = vec![0];
+    content.extend(request.into_bytes());
+    content
+}
+
+fn detecting() -> mould::server::Suite<common::Plain> {
+    let mut suite = suite();
+    suite.detect_codec(Tagged);
+    suite.detect_codec(JsonCodec::default());
+    suite
+}
+
+#[test]
+fn json_of_first_frame_is_detected() {
+    let mut flow = Frames::new(vec![Pull::Message(format!(" {}", echo(1, json!("a")))), Pull::Message(echo(2, json!("b")))]);
+    process_session(&detecting(), &mut flow);
+    assert_eq!(flow.texts.len(), 4);
+    assert!(flow.binaries.is_empty());
+    assert!(flow.texts[3].contains("\"done\""));
+}
+
+#[test]
+fn binary_codec_of_first_frame_is_kept() {
+    let mut flow = Frames::new(vec![Pull::Binary(tagged(echo(1, json!("a")))), Pull::Binary(tagged(echo(2, json!("b"))))]);
+    process_session(&detecting(), &mut flow);
+    assert!(flow.texts.is_empty());
+    let outputs: Vec<Value> = flow.binaries.iter().map(|content| serde_json::from_slice(&content[1..]).unwrap()).collect();
+    assert_eq!(outputs.len(), 4);
+    assert_eq!(outputs[2]["result"]["data"], json!("b"));
+}
+
+#[test]
+fn undetected_frame_is_read_by_default_codec() {
+    let mut flow = Frames::new(vec![Pull::Message("garbage".into()), Pull::Message(echo(2, json!("b")))]);
+    process_session(&detecting(), &mut flow);
+    let outputs: Vec<Output> = flow.texts.iter().map(|content| serde_json::from_str(content).unwrap()).collect();
+    assert_eq!(events(&outputs), vec![(0, "fail".into()), (2, "item".into()), (2, "done".into())]);
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack_of_first_frame_is_detected() {
+    use mould::codec::MsgPackCodec;
+    let mut suite = suite();
+    suite.detect_codec(JsonCodec::default());
+    suite.detect_codec(MsgPackCodec);
+    let request: Value = serde_json::from_str(&echo(1, json!("a"))).unwrap();
+    let mut flow = Frames::new(vec![Pull::Binary(rmp_serde::to_vec(&request).unwrap())]);
+    process_session(&suite, &mut flow);
+    assert!(flow.texts.is_empty());
+    let outputs: Vec<Value> = flow.binaries.iter().map(|content| rmp_serde::from_slice(content).unwrap()).collect();
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(outputs[0]["result"]["data"], json!("a"));
+}
@@ -0,0 +1,46 @@
+//! Sessions and services shared by the tests.
+
+#![allow(dead_code)]
+
+use serde_json::{self, Value};
+use mould::session::{DefaultBuilder, Output, TaskId};
+use mould::server::Suite;
+use mould::testing::MemoryFlow;
+use mould::Session;
+
+#[derive(Default)]
+pub struct Plain;
+
+impl Session for Plain {}
+
+/// Suite of the `echo` service, its `echo` action returns the payload.
+pub fn suite() -> Suite<Plain> {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register_fn("echo", "echo", |_: &mut Plain, payload: Value| Ok(payload));
+    suite
+}
+
+/// Request of the `echo` action.
+pub fn echo(id: TaskId, payload: Value) -> String {
+    json!({"id": id, "service": "echo", "action": "echo", "payload": payload}).to_string()
+}
+
+/// Id and event of every output.
+pub fn events(outputs: &[Output]) -> Vec<(TaskId, String)> {
+    outputs
+        .iter()
+        .map(|out| {
+            let result = serde_json::to_value(&out.result).unwrap();
+            (out.id, result["event"].as_str().unwrap_or_default().to_owned())
+        })
+        .collect()
+}
+
+/// Flow of a client which has sent the frames and closed.
+pub fn flow(frames: &[String]) -> MemoryFlow {
+    let mut flow = MemoryFlow::new("test");
+    for frame in frames {
+        flow.send(frame.clone());
+    }
+    flow
+}
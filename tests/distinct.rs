@@ -0,0 +1,79 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::session::DefaultBuilder;
+use common::{events, flow, Plain};
+
+/// Streams the letters of its payload.
+struct Letters;
+
+impl StreamWorker<Plain> for Letters {
+    type In = Vec<String>;
+    type Out = String;
+
+    fn perform(&mut self, _: &mut Plain, letters: Vec<String>, stream: &mut Stream<String>) -> worker::Result<()> {
+        for letter in letters {
+            stream.emit(letter)?;
+        }
+        Ok(())
+    }
+}
+
+/// Streams the letters of its payload in background.
+struct LaterLetters;
+
+impl BackgroundWorker<Plain> for LaterLetters {
+    type In = Vec<String>;
+    type Out = String;
+
+    fn prepare(&mut self, _: &mut Plain, letters: Vec<String>) -> worker::Result<Box<Job<String>>> {
+        let job = move |stream: &mut Stream<String>| {
+            for letter in letters {
+                stream.emit(letter)?;
+            }
+            Ok(())
+        };
+        Ok(Box::new(job))
+    }
+}
+
+struct LettersService;
+
+impl service::Service<Plain> for LettersService {
+    fn route(&self, action: &str) -> service::Result<Action<Plain>> {
+        match action {
+            "all" => Ok(Action::from_stream_worker(Letters)),
+            "distinct" => Ok(Action::from_stream_worker(Letters).distinct()),
+            "background" => Ok(Action::from_background_worker(LaterLetters).distinct()),
+            _ => Err(service::Error::ActionNotFound),
+        }
+    }
+}
+
+fn stream(action: &str) -> Vec<serde_json::Value> {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("letters", LettersService);
+    let request = json!({"id": 1, "service": "letters", "action": action, "payload": ["a", "a", "b", "b", "a"]});
+    let mut flow = flow(&[request.to_string()]);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(events(&outputs).last(), Some(&(1, "done".into())));
+    outputs
+        .iter()
+        .map(|out| serde_json::to_value(&out.result).unwrap())
+        .filter(|result| result["event"] == json!("item"))
+        .map(|result| result["data"].clone())
+        .collect()
+}
+
+#[test]
+fn adjacent_duplicates_are_dropped() {
+    assert_eq!(stream("all"), vec![json!("a"), json!("a"), json!("b"), json!("b"), json!("a")]);
+    assert_eq!(stream("distinct"), vec![json!("a"), json!("b"), json!("a")]);
+    assert_eq!(stream("background"), vec![json!("a"), json!("b"), json!("a")]);
+}
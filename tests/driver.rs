@@ -0,0 +1,59 @@
+#![cfg(feature = "wsmould")]
+
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+use mould::client::Client;
+use mould::server::wsmould;
+use common::suite;
+
+const CLIENTS: usize = 32;
+const THREADS: usize = 2;
+const HANDSHAKES: usize = 1;
+
+/// Threads of the test process, `None` off Linux.
+fn threads() -> Option<usize> {
+    fs::read_dir("/proc/self/task").ok().map(|tasks| tasks.count())
+}
+
+#[test]
+fn many_sessions_are_served_by_few_threads() {
+    let before = threads();
+    let mut suite = suite();
+    suite.set_session_threads(THREADS);
+    let config = wsmould::WsConfig {
+        handshake_threads: HANDSHAKES,
+        ..wsmould::WsConfig::default()
+    };
+    let handle = wsmould::spawn_with("127.0.0.1:0", Arc::new(suite), config);
+    let url = format!("ws://{}", handle.local_addr());
+
+    let mut clients: Vec<_> = (0..CLIENTS)
+        .map(|_| Client::connect(&url).expect("server accepts"))
+        .collect();
+    for (n, client) in clients.iter_mut().enumerate() {
+        let items: Vec<usize> = client.call("echo", "echo", n).unwrap();
+        assert_eq!(items, vec![n]);
+    }
+    assert_eq!(handle.sessions(), CLIENTS);
+
+    // The accept loop, the handshakes and the drivers
+    if let (Some(before), Some(after)) = (before, threads()) {
+        assert!(after <= before + 1 + HANDSHAKES + THREADS, "{} threads serve {} sessions", after - before, CLIENTS);
+    }
+    // Sessions of a thread still take turns
+    for (n, client) in clients.iter_mut().enumerate() {
+        let items: Vec<usize> = client.call("echo", "echo", n).unwrap();
+        assert_eq!(items, vec![n]);
+    }
+    handle.shutdown();
+    drop(clients);
+    assert!(handle.drain(Duration::from_secs(2)), "sessions are still running");
+    handle.join();
+}
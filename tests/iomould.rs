@@ -0,0 +1,99 @@
+#![cfg(feature = "iomould")]
+
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::server::iomould::IoFlow;
+use mould::session::DefaultBuilder;
+use common::{echo, Plain};
+
+/// Reader of chunks sent by the test, it blocks until one comes.
+struct Chunks(Receiver<Vec<u8>>);
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.recv() {
+            Ok(chunk) => {
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+/// Writer which the test reads while the session writes.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Later;
+
+impl BackgroundWorker<Plain> for Later {
+    type In = ();
+    type Out = &'static str;
+
+    fn prepare(&mut self, _: &mut Plain, _: ()) -> worker::Result<Box<Job<&'static str>>> {
+        let job = |stream: &mut Stream<&'static str>| stream.emit("later");
+        Ok(Box::new(job))
+    }
+}
+
+struct LaterService;
+
+impl service::Service<Plain> for LaterService {
+    fn route(&self, _: &str) -> service::Result<Action<Plain>> {
+        Ok(Action::from_background_worker(Later))
+    }
+}
+
+#[test]
+fn flow_reads_a_borrowed_reader() {
+    let input = format!("{}\n", echo(1, json!("a")));
+    let mut written = Vec::new();
+    {
+        let flow = IoFlow::new("borrowed", input.as_bytes(), &mut written);
+        process_session(&common::suite(), flow);
+    }
+    assert_eq!(String::from_utf8(written).unwrap().lines().count(), 2);
+}
+
+#[test]
+fn threaded_flow_sends_outputs_while_client_is_silent() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("later", LaterService);
+    let (tx, rx) = mpsc::channel();
+    let request = json!({"id": 1, "service": "later", "action": "", "payload": null});
+    tx.send(format!("{}\n", request).into_bytes()).unwrap();
+    let written = Shared::default();
+    let flow = IoFlow::new("threaded", Chunks(rx), written.clone()).threaded();
+    let session = thread::spawn(move || process_session(&suite, flow));
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while written.0.lock().unwrap().split(|b| *b == b'\n').count() < 3 {
+        assert!(Instant::now() < deadline, "outputs wait for the client");
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(tx);
+    session.join().unwrap();
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "iomould")]
+
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::Cursor;
+use mould::server::{process_session, Suite};
+use mould::server::iomould::IoFlow;
+use mould::session::Output;
+use common::{echo, events, suite, Plain};
+
+/// Performs the lines with messages limited to 80 bytes.
+fn run(suite: &Suite<Plain>, lines: &[String]) -> Vec<Output> {
+    let input = lines.join("\n") + "\n";
+    let mut written = Vec::new();
+    {
+        let flow = IoFlow::new("limits", Cursor::new(input), &mut written).max_line_length(80);
+        process_session(suite, flow);
+    }
+    String::from_utf8(written)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+fn lines() -> Vec<String> {
+    vec![echo(1, json!("x".repeat(64))), echo(2, json!("b"))]
+}
+
+#[test]
+fn oversized_message_is_rejected_and_session_goes_on() {
+    let outputs = run(&suite(), &lines());
+    assert_eq!(events(&outputs), vec![(0, "fail".into()), (2, "item".into()), (2, "done".into())]);
+    assert_eq!(serde_json::to_value(&outputs[0].result).unwrap()["data"]["code"], json!("TOO_LARGE"));
+}
+
+#[test]
+fn oversized_message_closes_session_when_asked() {
+    let mut suite = suite();
+    suite.set_close_on_oversized(true);
+    let outputs = run(&suite, &lines());
+    assert_eq!(events(&outputs), vec![(0, "fail".into())]);
+}
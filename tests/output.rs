@@ -0,0 +1,99 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::session::{DefaultBuilder, Output};
+use common::{events, flow, Plain};
+
+/// Streams `count` items of 10 bytes, it doesn't stop on errors.
+struct Flood;
+
+impl StreamWorker<Plain> for Flood {
+    type In = usize;
+    type Out = String;
+
+    fn perform(&mut self, _: &mut Plain, count: usize, stream: &mut Stream<String>) -> worker::Result<()> {
+        for _ in 0..count {
+            stream.emit("x".repeat(8)).ok();
+        }
+        Ok(())
+    }
+}
+
+/// Streams `count` items of 10 bytes in background.
+struct LaterFlood;
+
+impl BackgroundWorker<Plain> for LaterFlood {
+    type In = usize;
+    type Out = String;
+
+    fn prepare(&mut self, _: &mut Plain, count: usize) -> worker::Result<Box<Job<String>>> {
+        let job = move |stream: &mut Stream<String>| {
+            for _ in 0..count {
+                stream.emit("x".repeat(8))?;
+            }
+            Ok(())
+        };
+        Ok(Box::new(job))
+    }
+}
+
+struct FloodService;
+
+impl service::Service<Plain> for FloodService {
+    fn route(&self, action: &str) -> service::Result<Action<Plain>> {
+        match action {
+            "sync" => Ok(Action::from_stream_worker(Flood)),
+            "background" => Ok(Action::from_background_worker(LaterFlood)),
+            "budgeted" => Ok(Action::from_stream_worker(Flood).max_output_bytes(50)),
+            _ => Err(service::Error::ActionNotFound),
+        }
+    }
+}
+
+fn run(suite: &Suite<Plain>, action: &str, count: usize) -> Vec<Output> {
+    let request = json!({"id": 1, "service": "flood", "action": action, "payload": count});
+    let mut flow = flow(&[request.to_string()]);
+    process_session(suite, &mut flow);
+    flow.outputs()
+}
+
+fn suite(budget: usize) -> Suite<Plain> {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("flood", FloodService);
+    suite.set_max_output_bytes(budget);
+    suite
+}
+
+fn ended_too_large(outputs: &[Output], items: usize) {
+    let mut expected = vec![(1, "item".to_owned()); items];
+    expected.push((1, "fail".into()));
+    assert_eq!(events(outputs), expected);
+    let result = serde_json::to_value(&outputs[items].result).unwrap();
+    assert_eq!(result["data"]["code"], json!("TOO_LARGE"));
+}
+
+#[test]
+fn stream_over_budget_fails() {
+    let suite = suite(30);
+    ended_too_large(&run(&suite, "sync", 100), 3);
+    ended_too_large(&run(&suite, "background", 100), 3);
+}
+
+#[test]
+fn stream_within_budget_is_done() {
+    let suite = suite(30);
+    let mut expected = vec![(1, "item".to_owned()); 3];
+    expected.push((1, "done".into()));
+    assert_eq!(events(&run(&suite, "sync", 3)), expected);
+}
+
+#[test]
+fn budget_of_action_overrides_budget_of_suite() {
+    let suite = suite(30);
+    ended_too_large(&run(&suite, "budgeted", 100), 5);
+}
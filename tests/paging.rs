@@ -0,0 +1,53 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::time::{Duration, Instant};
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::session::DefaultBuilder;
+use common::{events, flow, Plain};
+
+/// Gives `count` numbers a page of two.
+struct Numbers;
+
+impl PagedWorker<Plain> for Numbers {
+    type In = u64;
+    type Out = u64;
+
+    fn page_size(&self) -> usize {
+        2
+    }
+
+    fn prepare(&mut self, _: &mut Plain, count: u64) -> worker::Result<Box<Iterator<Item = u64> + Send>> {
+        Ok(Box::new(0..count))
+    }
+}
+
+struct NumbersService;
+
+impl service::Service<Plain> for NumbersService {
+    fn route(&self, _: &str) -> service::Result<Action<Plain>> {
+        Ok(Action::from_paged_worker(Numbers))
+    }
+}
+
+#[test]
+fn task_fails_if_next_page_is_not_asked_for() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("numbers", NumbersService);
+    suite.set_page_wait(Duration::from_millis(50));
+    let request = json!({"id": 1, "service": "numbers", "action": "count", "payload": 10});
+    let mut flow = flow(&[request.to_string()]);
+    let begun = Instant::now();
+    process_session(&suite, &mut flow);
+    assert!(begun.elapsed() < Duration::from_secs(1));
+    let outputs = flow.outputs();
+    assert_eq!(
+        events(&outputs),
+        vec![(1, "item".into()), (1, "item".into()), (1, "more".into()), (1, "fail".into())]
+    );
+    assert_eq!(serde_json::to_value(&outputs[3].result).unwrap()["data"]["code"], json!("TIMEOUT"));
+}
@@ -0,0 +1,59 @@
+extern crate mould;
+
+use mould::permission::{self, Require, Rights};
+
+#[derive(Debug, PartialEq)]
+enum Right {
+    Read,
+    Write,
+}
+
+impl Rights for Right {
+    fn name(&self) -> Option<String> {
+        Some(format!("{:?}", self))
+    }
+}
+
+/// Session which checks rights itself instead of with `HasRight`.
+struct Reader;
+
+impl Require<Right> for Reader {
+    fn require(&self, right: &Right) -> permission::Result<()> {
+        if *right == Right::Read {
+            Ok(())
+        } else {
+            Err(permission::Error::AccessDenied)
+        }
+    }
+}
+
+/// Right which has no name.
+struct Anonymous;
+
+impl Rights for Anonymous {}
+
+impl Require<Anonymous> for Reader {
+    fn require(&self, _: &Anonymous) -> permission::Result<()> {
+        Err(permission::Error::AccessDenied)
+    }
+}
+
+#[test]
+fn any_and_all_are_checked_with_require() {
+    assert!(Reader.require_any(&[Right::Write, Right::Read]).is_ok());
+    assert!(Reader.require_all(&[Right::Read]).is_ok());
+    let denied = Reader.require_all(&[Right::Read, Right::Write]).unwrap_err();
+    assert_eq!(denied.to_string(), "access denied, also requires: Write");
+}
+
+#[test]
+fn empty_lists_are_explicit() {
+    assert!(Reader.require_any(&[] as &[Right]).is_err());
+    assert!(Reader.require_all(&[] as &[Right]).is_ok());
+}
+
+#[test]
+fn unnamed_rights_are_denied_without_names() {
+    let denied = Reader.require_all(&[Anonymous]).unwrap_err();
+    assert_eq!(denied.to_string(), "access denied");
+}
@@ -0,0 +1,119 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+use std::sync::Arc;
+use std::time::Duration;
+use serde_json::Value;
+use mould::flow::{self, ConnectionInfo, Flow};
+use mould::resume::{MemoryStore, SessionStore, SnapshotStore, RESUME_HEADER};
+use mould::server::{process_session, Suite};
+use mould::session::{BuildError, Builder, Output, Persistable, TaskResult};
+use mould::testing::MemoryFlow;
+use mould::Session;
+
+/// Session which counts its requests.
+#[derive(Default)]
+struct Counter(u64);
+
+impl Session for Counter {}
+
+impl Persistable for Counter {
+    fn snapshot(&self) -> Value {
+        json!(self.0)
+    }
+}
+
+struct Counters;
+
+impl Builder<Counter> for Counters {
+    fn build(&self, _: &ConnectionInfo) -> Result<Counter, BuildError> {
+        Ok(Counter::default())
+    }
+
+    fn restore(&self, snapshot: Value) -> Result<Counter, BuildError> {
+        snapshot.as_u64().map(Counter).ok_or_else(|| "bad snapshot".into())
+    }
+}
+
+/// Store of snapshots shared by nodes.
+#[derive(Clone)]
+struct Shared(Arc<MemoryStore<Value>>);
+
+impl SessionStore<Value> for Shared {
+    fn save(&self, token: String, snapshot: Value) {
+        self.0.save(token, snapshot)
+    }
+
+    fn restore(&self, token: &str) -> Option<Value> {
+        self.0.restore(token)
+    }
+}
+
+/// Suite of a node, `count` returns the requests counted so far.
+fn node(shared: &Shared) -> Suite<Counter> {
+    let mut suite = Suite::new(Counters);
+    suite.register_fn("count", "count", |counter: &mut Counter, _: Value| {
+        counter.0 += 1;
+        Ok(counter.0)
+    });
+    suite.set_session_store(SnapshotStore::new(shared.clone(), Counters));
+    suite
+}
+
+/// Client which presents a token to resume its session.
+struct Resuming(MemoryFlow, String);
+
+impl Flow for Resuming {
+    fn who(&self) -> String {
+        self.0.who()
+    }
+
+    fn info(&self) -> ConnectionInfo {
+        let headers = vec![(RESUME_HEADER.to_owned(), self.1.clone())];
+        ConnectionInfo::new(self.who(), None).handshake("/".into(), headers, None)
+    }
+
+    fn pull(&mut self) -> flow::Result<Option<String>> {
+        self.0.pull()
+    }
+
+    fn push(&mut self, content: String) -> flow::Result<()> {
+        self.0.push(content)
+    }
+}
+
+fn count(id: u64) -> String {
+    json!({"id": id, "service": "count", "action": "count", "payload": null}).to_string()
+}
+
+fn data(outputs: &[Output]) -> Vec<Value> {
+    outputs
+        .iter()
+        .filter_map(|out| match out.result {
+            TaskResult::Item(ref value) => Some(value.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn session_moves_to_another_node_by_snapshot() {
+    let shared = Shared(Arc::new(MemoryStore::new(Duration::from_secs(60))));
+    let mut flow = MemoryFlow::new("first");
+    flow.send(count(1));
+    flow.send(count(2));
+    process_session(&node(&shared), &mut flow);
+    let outputs = flow.outputs();
+    let token = match outputs[0].result {
+        TaskResult::Resume { ref token } => token.clone(),
+        _ => panic!("no resume token"),
+    };
+    assert_eq!(data(&outputs), vec![json!(1), json!(2)]);
+
+    let mut flow = MemoryFlow::new("second");
+    flow.send(count(3));
+    let mut flow = Resuming(flow, token);
+    process_session(&node(&shared), &mut flow);
+    assert_eq!(data(&flow.0.outputs()), vec![json!(3)]);
+}
@@ -0,0 +1,108 @@
+#![cfg(feature = "wsmould")]
+
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use mould::client::Client;
+use mould::server::wsmould;
+use common::suite;
+
+#[test]
+fn shutdown_stops_listener_and_sessions() {
+    let handle = wsmould::spawn("127.0.0.1:0", Arc::new(suite()));
+    let addr = handle.local_addr();
+    let mut client = Client::connect(&format!("ws://{}", addr)).expect("server accepts");
+    let items: Vec<String> = client.call("echo", "echo", "hello").unwrap();
+    assert_eq!(items, vec!["hello".to_owned()]);
+    assert_eq!(handle.sessions(), 1);
+
+    let begun = Instant::now();
+    handle.shutdown();
+    drop(client);
+    assert!(handle.drain(Duration::from_secs(2)), "session is still running");
+    handle.join();
+    assert!(begun.elapsed() < Duration::from_secs(3));
+    // Give the OS a moment to release the port
+    thread::sleep(Duration::from_millis(50));
+    assert!(TcpStream::connect(addr).is_err(), "listener is still open");
+}
+
+#[test]
+fn silent_client_does_not_hold_up_others() {
+    let handle = wsmould::spawn("127.0.0.1:0", Arc::new(suite()));
+    let addr = handle.local_addr();
+    // Connects and never sends its upgrade request
+    let _silent = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let begun = Instant::now();
+    let mut client = Client::connect(&format!("ws://{}", addr)).expect("server accepts");
+    let items: Vec<String> = client.call("echo", "echo", "hello").unwrap();
+    assert_eq!(items, vec!["hello".to_owned()]);
+    assert!(begun.elapsed() < Duration::from_secs(1));
+    handle.shutdown();
+    drop(client);
+    handle.join();
+}
+
+#[test]
+fn silent_client_is_dropped_after_handshake_timeout() {
+    let config = wsmould::WsConfig {
+        handshake_timeout: Duration::from_millis(100),
+        ..wsmould::WsConfig::default()
+    };
+    let handle = wsmould::spawn_with("127.0.0.1:0", Arc::new(suite()), config);
+    let mut silent = TcpStream::connect(handle.local_addr()).unwrap();
+    silent.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let mut rest = Vec::new();
+    assert!(silent.read_to_end(&mut rest).is_ok(), "connection is still open");
+    assert!(handle.drain(Duration::from_secs(1)), "handshake is still running");
+    handle.shutdown();
+    handle.join();
+}
+
+#[test]
+fn trickling_client_is_dropped_at_handshake_deadline() {
+    let config = wsmould::WsConfig {
+        handshake_timeout: Duration::from_millis(300),
+        ..wsmould::WsConfig::default()
+    };
+    let handle = wsmould::spawn_with("127.0.0.1:0", Arc::new(suite()), config);
+    let mut slow = TcpStream::connect(handle.local_addr()).unwrap();
+    let begun = Instant::now();
+    // Every byte comes in time for a read, the handshake never ends
+    while slow.write_all(b"G").is_ok() && begun.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(begun.elapsed() < Duration::from_secs(1), "connection is still open");
+    handle.shutdown();
+    handle.join();
+}
+
+#[test]
+fn client_over_limit_is_told_the_server_is_busy() {
+    let mut suite = suite();
+    suite.set_max_connections(1);
+    let handle = wsmould::spawn("127.0.0.1:0", Arc::new(suite));
+    let addr = handle.local_addr();
+    let mut client = Client::connect(&format!("ws://{}", addr)).expect("server accepts");
+    let items: Vec<String> = client.call("echo", "echo", "hello").unwrap();
+    assert_eq!(items, vec!["hello".to_owned()]);
+
+    let mut extra = TcpStream::connect(addr).unwrap();
+    extra.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let mut answer = String::new();
+    extra.read_to_string(&mut answer).expect("server closes the connection");
+    assert!(answer.starts_with("HTTP/1.1 503"), "answer is {:?}", answer);
+    handle.shutdown();
+    drop(client);
+    handle.join();
+}
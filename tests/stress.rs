@@ -0,0 +1,110 @@
+#![cfg(feature = "iomould")]
+
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::io::{self, Cursor, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::server::iomould::IoFlow;
+use mould::session::{DefaultBuilder, Output, TaskResult};
+use common::Plain;
+
+const SESSIONS: usize = 4;
+const TASKS: usize = 8;
+const ITEMS: u64 = 250;
+
+/// Emits numbers from a task thread as fast as it can.
+struct Numbers;
+
+impl BackgroundWorker<Plain> for Numbers {
+    type In = u64;
+    type Out = u64;
+
+    fn prepare(&mut self, _: &mut Plain, count: u64) -> worker::Result<Box<Job<u64>>> {
+        let job = move |stream: &mut Stream<u64>| {
+            for n in 0..count {
+                stream.emit(n)?;
+            }
+            Ok(())
+        };
+        Ok(Box::new(job))
+    }
+}
+
+struct NumbersService;
+
+impl service::Service<Plain> for NumbersService {
+    fn route(&self, _: &str) -> service::Result<Action<Plain>> {
+        Ok(Action::from_background_worker(Numbers))
+    }
+}
+
+/// Bytes written to a socket.
+#[derive(Clone, Default)]
+struct Wire(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Wire {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a session of many tasks and returns what it has written.
+fn session(suite: Arc<Suite<Plain>>) -> Vec<u8> {
+    let mut requests = Vec::new();
+    for id in 1..TASKS + 1 {
+        let request = json!({"id": id, "service": "numbers", "action": "count", "payload": ITEMS});
+        writeln!(requests, "{}", request).unwrap();
+    }
+    let wire = Wire::default();
+    let flow = IoFlow::new("stress", Cursor::new(requests), wire.clone());
+    process_session(suite.as_ref(), flow);
+    let written = wire.0.lock().unwrap().clone();
+    written
+}
+
+#[test]
+fn outputs_of_concurrent_tasks_are_never_interleaved() {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("numbers", NumbersService);
+    let suite = Arc::new(suite);
+    let sessions: Vec<_> = (0..SESSIONS)
+        .map(|_| {
+            let suite = suite.clone();
+            thread::spawn(move || session(suite))
+        })
+        .collect();
+    for session in sessions {
+        let written = session.join().expect("session panicked");
+        let text = String::from_utf8(written).expect("frames are corrupted");
+        let mut next = vec![0; TASKS + 1];
+        let mut done = vec![false; TASKS + 1];
+        for line in text.lines() {
+            let out: Output = serde_json::from_str(line).expect("frame is corrupted");
+            assert!(!done[out.id], "output after done");
+            match out.result {
+                TaskResult::Item(ref item) => {
+                    assert_eq!(item.as_u64(), Some(next[out.id]));
+                    next[out.id] += 1;
+                }
+                TaskResult::Done => done[out.id] = true,
+                _ => panic!("unexpected output: {}", line),
+            }
+        }
+        for id in 1..TASKS + 1 {
+            assert_eq!(next[id], ITEMS);
+            assert!(done[id]);
+        }
+    }
+}
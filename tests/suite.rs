@@ -0,0 +1,81 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use mould::server::{process_session, ErrorContext, INTROSPECT_SERVICE};
+use mould::timesync::TimeService;
+use mould::testing::run_request;
+use mould::session::Input;
+use common::{echo, events, flow, suite, Plain};
+
+fn request(service: &str, action: &str) -> Input {
+    Input {
+        id: 1,
+        service: service.to_owned(),
+        action: action.to_owned(),
+        payload: json!(null),
+        timeout_ms: None,
+    }
+}
+
+#[test]
+fn registering_a_name_again_replaces_the_service() {
+    let mut suite = suite();
+    suite.register_fn("echo", "shout", |_: &mut Plain, text: String| Ok(text.to_uppercase()));
+    assert_eq!(suite.service_names(), vec!["echo"]);
+    let mut flow = flow(&[echo(1, json!("a"))]);
+    process_session(&suite, &mut flow);
+    let outputs = flow.outputs();
+    assert_eq!(events(&outputs), vec![(1, "fail".into())]);
+    assert_eq!(serde_json::to_value(&outputs[0].result).unwrap()["data"]["code"], json!("ACTION_NOT_FOUND"));
+}
+
+#[test]
+fn unregistered_service_is_not_found() {
+    let mut suite = suite();
+    assert!(suite.has_service("echo"));
+    assert!(suite.unregister("echo"));
+    assert!(!suite.has_service("echo"));
+    let outputs = run_request(&suite, Plain, request("echo", "echo"));
+    assert_eq!(serde_json::to_value(&outputs[0].result).unwrap()["data"]["code"], json!("SERVICE_NOT_FOUND"));
+}
+
+#[test]
+fn removing_a_missing_name_changes_nothing() {
+    let mut suite = suite();
+    assert!(!suite.unregister("missing"));
+    assert_eq!(suite.service_names(), vec!["echo"]);
+}
+
+#[test]
+fn error_handler_knows_the_request() {
+    let mut suite = suite();
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let sink = reported.clone();
+    suite.on_error(Box::new(move |_, context: &ErrorContext| {
+        let action = context.action.map(str::to_owned);
+        sink.lock().unwrap().push((context.id, action));
+    }));
+    let mut input = request("echo", "missing");
+    input.id = 7;
+    run_request(&suite, Plain, input);
+    assert_eq!(*reported.lock().unwrap(), vec![(7, Some("missing".to_owned()))]);
+}
+
+#[test]
+fn introspection_lists_actions_of_time_service() {
+    let mut suite = suite();
+    suite.register("time", TimeService);
+    let outputs = run_request(&suite, Plain, request(INTROSPECT_SERVICE, ""));
+    let services = serde_json::to_value(&outputs[0].result).unwrap()["data"].clone();
+    assert_eq!(services["time"], json!(["now", "sync"]));
+}
+
+#[test]
+#[should_panic(expected = "at least one thread")]
+fn zero_session_threads_is_refused() {
+    suite().set_session_threads(0);
+}
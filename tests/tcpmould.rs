@@ -0,0 +1,72 @@
+#![cfg(feature = "tcpmould")]
+
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::sync::Arc;
+use std::time::Duration;
+use mould::server::tcpmould;
+use common::{echo, suite};
+
+#[test]
+fn client_sees_the_end_when_server_closes_a_session() {
+    let mut suite = suite();
+    suite.set_idle_timeout(Duration::from_millis(100));
+    let handle = tcpmould::spawn("127.0.0.1:0", Arc::new(suite));
+    let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    writeln!(stream, "{}", echo(1, json!("a"))).unwrap();
+
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    assert!(line.contains("\"a\""));
+    // The client is silent, the server drops it and the socket ends
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).expect("the server shuts the socket down");
+    assert!(handle.drain(Duration::from_secs(2)), "session is still running");
+    handle.shutdown();
+}
+
+#[test]
+fn driver_polls_sessions_without_reader_threads() {
+    const CLIENTS: usize = 16;
+    const THREADS: usize = 2;
+    let before = fs::read_dir("/proc/self/task").ok().map(|tasks| tasks.count());
+    let mut suite = suite();
+    suite.set_session_threads(THREADS);
+    let handle = tcpmould::spawn("127.0.0.1:0", Arc::new(suite));
+    let mut clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let stream = TcpStream::connect(handle.local_addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            BufReader::new(stream)
+        })
+        .collect();
+    for (n, client) in clients.iter_mut().enumerate() {
+        // A message split across two writes is taken when it's whole
+        let request = format!("{}\n", echo(1, json!(n)));
+        let (head, tail) = request.split_at(request.len() / 2);
+        client.get_mut().write_all(head.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        client.get_mut().write_all(tail.as_bytes()).unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        assert!(line.contains(&format!("\"data\":{}}}", n)), "unexpected answer {}", line);
+    }
+    assert_eq!(handle.sessions(), CLIENTS);
+    // The accept loop and the drivers
+    if let (Some(before), Some(after)) = (before, fs::read_dir("/proc/self/task").ok().map(|tasks| tasks.count())) {
+        assert!(after <= before + 1 + THREADS, "{} threads serve {} sessions", after - before, CLIENTS);
+    }
+    drop(clients);
+    assert!(handle.drain(Duration::from_secs(2)), "sessions are still running");
+    handle.shutdown();
+}
@@ -0,0 +1,157 @@
+extern crate mould;
+#[macro_use]
+extern crate serde_json;
+
+mod common;
+
+use std::thread;
+use std::time::{Duration, Instant};
+use mould::prelude::*;
+use mould::server::{process_session, Suite};
+use mould::session::{DefaultBuilder, Output};
+use common::{events, flow, Plain};
+
+/// Sleeps for the milliseconds of its payload in background.
+struct Sleeper;
+
+impl BackgroundWorker<Plain> for Sleeper {
+    type In = u64;
+    type Out = ();
+
+    fn prepare(&mut self, _: &mut Plain, ms: u64) -> worker::Result<Box<Job<()>>> {
+        let job = move |_: &mut Stream<()>| {
+            thread::sleep(Duration::from_millis(ms));
+            Ok(())
+        };
+        Ok(Box::new(job))
+    }
+}
+
+/// Sleeps for the milliseconds of its payload on the session thread.
+struct Napper;
+
+impl Worker<Plain> for Napper {
+    type In = u64;
+    type Out = ();
+
+    fn perform(&mut self, _: &mut Plain, ms: u64) -> worker::Result<()> {
+        thread::sleep(Duration::from_millis(ms));
+        Ok(())
+    }
+}
+
+struct SleepService;
+
+impl service::Service<Plain> for SleepService {
+    fn route(&self, action: &str) -> service::Result<Action<Plain>> {
+        match action {
+            "background" => Ok(Action::from_background_worker(Sleeper)),
+            "short" => Ok(Action::from_background_worker(Sleeper).timeout(Duration::from_millis(100))),
+            "long" => Ok(Action::from_background_worker(Sleeper).timeout(Duration::from_secs(2))),
+            "sync" => Ok(Action::from_worker(Napper)),
+            _ => Err(service::Error::ActionNotFound),
+        }
+    }
+}
+
+fn suite() -> Suite<Plain> {
+    let mut suite = Suite::new(DefaultBuilder);
+    suite.register("sleep", SleepService);
+    suite
+}
+
+fn run(suite: &Suite<Plain>, requests: &[serde_json::Value]) -> Vec<Output> {
+    let frames: Vec<String> = requests.iter().map(|request| request.to_string()).collect();
+    let mut flow = flow(&frames);
+    process_session(suite, &mut flow);
+    flow.outputs()
+}
+
+fn code(output: &Output) -> serde_json::Value {
+    serde_json::to_value(&output.result).unwrap()["data"]["code"].clone()
+}
+
+#[test]
+fn background_task_fails_after_suite_timeout() {
+    let mut suite = suite();
+    suite.set_task_timeout(Duration::from_millis(100));
+    let begun = Instant::now();
+    let outputs = run(&suite, &[json!({"id": 1, "service": "sleep", "action": "background", "payload": 2000})]);
+    assert_eq!(events(&outputs), vec![(1, "fail".into())]);
+    assert_eq!(code(&outputs[0]), json!("TIMEOUT"));
+    assert!(begun.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn sync_worker_runs_untimed_under_suite_timeout() {
+    let mut suite = suite();
+    suite.set_task_timeout(Duration::from_millis(100));
+    let outputs = run(&suite, &[
+        json!({"id": 1, "service": "sleep", "action": "sync", "payload": 200}),
+        json!({"id": 2, "service": "sleep", "action": "background", "payload": 2000}),
+    ]);
+    assert_eq!(events(&outputs), vec![(1, "item".into()), (1, "done".into()), (2, "fail".into())]);
+    assert_eq!(code(&outputs[2]), json!("TIMEOUT"));
+}
+
+#[test]
+fn background_task_fails_after_timeout_of_request() {
+    let suite = suite();
+    let begun = Instant::now();
+    let request = json!({"id": 1, "service": "sleep", "action": "background", "payload": 2000, "timeout_ms": 100});
+    let outputs = run(&suite, &[request]);
+    assert_eq!(events(&outputs), vec![(1, "fail".into())]);
+    assert_eq!(code(&outputs[0]), json!("TIMEOUT"));
+    let elapsed = begun.elapsed();
+    assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(1));
+}
+
+#[test]
+fn timeout_of_request_to_sync_worker_is_ignored() {
+    let suite = suite();
+    let request = json!({"id": 1, "service": "sleep", "action": "sync", "payload": 200, "timeout_ms": 100});
+    let outputs = run(&suite, &[request]);
+    assert_eq!(events(&outputs), vec![(1, "item".into()), (1, "done".into())]);
+}
+
+#[test]
+fn timeout_of_action_overrides_suite_timeout() {
+    let mut suite = suite();
+    suite.set_task_timeout(Duration::from_millis(100));
+    let outputs = run(&suite, &[
+        json!({"id": 1, "service": "sleep", "action": "long", "payload": 300}),
+        json!({"id": 2, "service": "sleep", "action": "background", "payload": 300}),
+    ]);
+    let mut ended = events(&outputs);
+    ended.sort();
+    assert_eq!(ended, vec![(1, "done".into()), (2, "fail".into())]);
+
+    let mut suite = self::suite();
+    suite.set_task_timeout(Duration::from_secs(2));
+    let begun = Instant::now();
+    let outputs = run(&suite, &[json!({"id": 1, "service": "sleep", "action": "short", "payload": 2000})]);
+    assert_eq!(events(&outputs), vec![(1, "fail".into())]);
+    assert!(begun.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn timeout_of_action_applies_without_suite_timeout() {
+    let suite = suite();
+    let outputs = run(&suite, &[
+        json!({"id": 1, "service": "sleep", "action": "short", "payload": 300}),
+        json!({"id": 2, "service": "sleep", "action": "background", "payload": 300}),
+    ]);
+    let mut ended = events(&outputs);
+    ended.sort();
+    assert_eq!(ended, vec![(1, "fail".into()), (2, "done".into())]);
+}
+
+#[test]
+fn timeout_of_request_shortens_timeout_of_action() {
+    let suite = suite();
+    let begun = Instant::now();
+    let request = json!({"id": 1, "service": "sleep", "action": "long", "payload": 2000, "timeout_ms": 100});
+    let outputs = run(&suite, &[request]);
+    assert_eq!(events(&outputs), vec![(1, "fail".into())]);
+    assert!(begun.elapsed() < Duration::from_secs(1));
+}